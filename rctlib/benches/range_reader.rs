@@ -0,0 +1,132 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+// Run with: cargo bench
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rctlib::*;
+
+const DISK_SIZE: u64 = 256 * 1024 * 1024;
+const NETWORK_CHUNK_SIZE: usize = 64 * 1024;
+
+// File backed disk, removed when dropped
+struct TestDisk {
+    path: PathBuf,
+}
+
+impl TestDisk {
+    fn new(name: &str) -> TestDisk {
+        let path = std::env::temp_dir().join(format!("rctlib-bench-{}.img", name));
+        let mut file = File::create(&path).unwrap();
+        let buf: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        for _ in 0..DISK_SIZE / buf.len() as u64 {
+            file.write_all(&buf).unwrap();
+        }
+        TestDisk { path }
+    }
+}
+
+impl Drop for TestDisk {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn bench_ranges(
+    c: &mut Criterion,
+    name: &str,
+    ranges: Vec<VirtualDiskChangeRange>,
+    read_size: usize,
+    read_ahead: usize,
+) {
+    let disk = TestDisk::new(name);
+    let mut group = c.benchmark_group("range_reader");
+    // Each iteration reads the whole disk
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(ranges.iter().map(|r| r.length).sum()));
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let file = File::open(&disk.path).unwrap();
+            let mut reader =
+                RangeReader::new(file, &ranges, DISK_SIZE, read_size, read_ahead, SECTOR_SIZE);
+            let mut buf = vec![0u8; NETWORK_CHUNK_SIZE];
+            let mut total = 0;
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => total += read,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            total
+        })
+    });
+    group.finish();
+}
+
+fn full_disk() -> Vec<VirtualDiskChangeRange> {
+    vec![VirtualDiskChangeRange {
+        offset: 0,
+        length: DISK_SIZE,
+    }]
+}
+
+// 64 KiB ranges every 256 KiB, similar to a fragmented RCT change list
+fn fragmented() -> Vec<VirtualDiskChangeRange> {
+    (0..DISK_SIZE / (256 * 1024))
+        .map(|i| VirtualDiskChangeRange {
+            offset: i * 256 * 1024,
+            length: 64 * 1024,
+        })
+        .collect()
+}
+
+fn full_disk_read_sizes(c: &mut Criterion) {
+    bench_ranges(c, "full-64k", full_disk(), 64 * 1024, DEFAULT_READ_AHEAD);
+    bench_ranges(c, "full-1m", full_disk(), 1024 * 1024, DEFAULT_READ_AHEAD);
+    bench_ranges(
+        c,
+        "full-8m",
+        full_disk(),
+        8 * 1024 * 1024,
+        DEFAULT_READ_AHEAD,
+    );
+}
+
+fn full_disk_no_read_ahead(c: &mut Criterion) {
+    bench_ranges(c, "full-no-ra", full_disk(), DEFAULT_READ_SIZE, 0);
+}
+
+fn fragmented_read_size_1m(c: &mut Criterion) {
+    bench_ranges(
+        c,
+        "frag-1m",
+        fragmented(),
+        DEFAULT_READ_SIZE,
+        DEFAULT_READ_AHEAD,
+    );
+}
+
+criterion_group!(
+    benches,
+    full_disk_read_sizes,
+    full_disk_no_read_ahead,
+    fragmented_read_size_1m
+);
+criterion_main!(benches);
@@ -0,0 +1,192 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Manages a deduplicating backup repository, importing the data fetched
+//! from the service or stored by its backup jobs.
+
+extern crate rctlib;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+
+use rctlib::*;
+
+const USAGE: &str = "Usage:
+    rct-repo create <repository> [<chunk size>]
+    rct-repo list <repository>
+    rct-repo import <repository> <name> <raw or VHDX image>
+    rct-repo import-changes <repository> <name> <parent> <change set JSON>
+    rct-repo restore <repository> <name> <raw image>
+    rct-repo hashes <repository> <name>
+    rct-repo delete <repository> <name>
+    rct-repo gc <repository>
+    rct-repo check <repository>";
+
+const COPY_SIZE: usize = 1024 * 1024;
+
+fn print_json<T: serde::Serialize>(value: &T) -> io::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn import_image(repository: &ChunkRepository, name: &str, path: &str) -> io::Result<()> {
+    let (mut image, disk_size): (Box<dyn Read>, u64) = match VhdxReader::open_chain(path) {
+        Ok(reader) => {
+            let disk_size = reader.virtual_size();
+            (Box::new(reader), disk_size)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            let mut file = File::open(path)?;
+            let disk_size = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(0))?;
+            (Box::new(file), disk_size)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut writer = repository.backup(name, None, disk_size)?;
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut offset = 0;
+    while offset < disk_size {
+        let length = std::cmp::min(COPY_SIZE as u64, disk_size - offset) as usize;
+        image.read_exact(&mut buf[..length])?;
+        writer.write_at(offset, &buf[..length])?;
+        offset += length as u64;
+    }
+    print_json(&writer.finish()?)
+}
+
+fn import_changes(
+    repository: &ChunkRepository,
+    name: &str,
+    parent: &str,
+    path: &str,
+) -> io::Result<()> {
+    let change_set: ChangeSet = serde_json::from_reader(File::open(path)?)?;
+    let disk_size = repository.manifest(parent)?.disk_size;
+    let mut ranges = change_set.ranges.clone();
+    let mut data = File::open(&change_set.data_path)?;
+
+    // The data is in the order of the ranges, which are written sorted
+    let mut data_offsets = Vec::new();
+    let mut data_offset = 0;
+    for range in ranges.iter() {
+        data_offsets.push(data_offset);
+        data_offset += range.length;
+    }
+    let mut order: Vec<usize> = (0..ranges.len()).collect();
+    order.sort_by_key(|i| ranges[*i].offset);
+
+    let mut writer = repository.backup(name, Some(parent), disk_size)?;
+    let mut buf = vec![0u8; COPY_SIZE];
+    for i in order {
+        let range = &mut ranges[i];
+        data.seek(SeekFrom::Start(data_offsets[i]))?;
+        while range.length > 0 {
+            let length = std::cmp::min(COPY_SIZE as u64, range.length) as usize;
+            data.read_exact(&mut buf[..length])?;
+            writer.write_at(range.offset, &buf[..length])?;
+            range.offset += length as u64;
+            range.length -= length as u64;
+        }
+    }
+    print_json(&writer.finish()?)
+}
+
+fn restore(repository: &ChunkRepository, name: &str, path: &str) -> io::Result<()> {
+    let mut reader = repository.reader(name)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    // Sparse, chunks not present are left as holes
+    file.set_len(reader.disk_size())?;
+    let mut buf = vec![0u8; reader.chunk_size() as usize];
+    for offset in reader.chunk_offsets() {
+        let length = std::cmp::min(reader.chunk_size(), reader.disk_size() - offset) as usize;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut buf[..length])?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&buf[..length])?;
+    }
+    file.sync_all()
+}
+
+fn run(args: &[String]) -> io::Result<bool> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args[..] {
+        ["create", root] => {
+            ChunkRepository::create(root, DEFAULT_CHUNK_SIZE)?;
+        }
+        ["create", root, chunk_size] => {
+            let chunk_size = chunk_size.parse::<u64>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: {}", chunk_size, e),
+                )
+            })?;
+            ChunkRepository::create(root, chunk_size)?;
+        }
+        ["list", root] => {
+            let repository = ChunkRepository::open(root)?;
+            for name in repository.manifests()? {
+                let manifest = repository.manifest(&name)?;
+                println!(
+                    "{}\t{}\t{}",
+                    name,
+                    manifest.parent.as_deref().unwrap_or("-"),
+                    manifest.disk_size
+                );
+            }
+        }
+        ["import", root, name, path] => {
+            import_image(&ChunkRepository::open(root)?, name, path)?;
+        }
+        ["import-changes", root, name, parent, path] => {
+            import_changes(&ChunkRepository::open(root)?, name, parent, path)?;
+        }
+        ["restore", root, name, path] => {
+            restore(&ChunkRepository::open(root)?, name, path)?;
+        }
+        ["hashes", root, name] => {
+            print_json(&ChunkRepository::open(root)?.block_hashes(name)?)?;
+        }
+        ["delete", root, name] => {
+            ChunkRepository::open(root)?.delete(name)?;
+        }
+        ["gc", root] => {
+            print_json(&ChunkRepository::open(root)?.gc()?)?;
+        }
+        ["check", root] => {
+            let summary = ChunkRepository::open(root)?.check()?;
+            print_json(&summary)?;
+            return Ok(summary.errors.is_empty());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use std::io::{self, Read};
+
+use super::VirtualDiskChangeRange;
+
+/// Magic of serialized block maps. It is followed by the block size, the disk
+/// size and the number of blocks as big endian u64 values, then by a bit per
+/// block, least significant bit first.
+pub const BLOCK_MAP_MAGIC: &[u8; 8] = b"RCTBMAP1";
+pub const BLOCK_MAP_HEADER_SIZE: usize = 32;
+
+fn invalid_block_map(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid block map: {}", msg),
+    )
+}
+
+/// Set of the fixed size blocks of a disk holding data.
+#[derive(Debug, Clone)]
+pub struct BlockMap {
+    block_size: u64,
+    disk_size: u64,
+    bits: Vec<u64>,
+}
+
+impl BlockMap {
+    pub fn new(block_size: u64, disk_size: u64) -> BlockMap {
+        let blocks = disk_size.div_ceil(block_size);
+        BlockMap {
+            block_size,
+            disk_size,
+            bits: vec![0; blocks.div_ceil(64) as usize],
+        }
+    }
+
+    /// Block map of the blocks covered by the given ranges.
+    pub fn from_ranges(
+        block_size: u64,
+        disk_size: u64,
+        ranges: &[VirtualDiskChangeRange],
+    ) -> BlockMap {
+        let mut map = BlockMap::new(block_size, disk_size);
+        map.set_ranges(ranges);
+        map
+    }
+
+    /// Parses a block map serialized by to_bytes.
+    pub fn from_bytes(data: &[u8]) -> io::Result<BlockMap> {
+        if data.len() < BLOCK_MAP_HEADER_SIZE || &data[..8] != BLOCK_MAP_MAGIC {
+            return Err(invalid_block_map("bad header"));
+        }
+        let read_u64 = |pos: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&data[pos..pos + 8]);
+            u64::from_be_bytes(buf)
+        };
+        let block_size = read_u64(8);
+        let disk_size = read_u64(16);
+        let blocks = read_u64(24);
+        if block_size == 0 || blocks != disk_size.div_ceil(block_size) {
+            return Err(invalid_block_map("bad block count"));
+        }
+        let bits = &data[BLOCK_MAP_HEADER_SIZE..];
+        if bits.len() as u64 != blocks.div_ceil(8) {
+            return Err(invalid_block_map("bad length"));
+        }
+
+        let mut map = BlockMap::new(block_size, disk_size);
+        for (i, chunk) in bits.chunks(8).enumerate() {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            map.bits[i] = u64::from_le_bytes(word);
+        }
+        // Padding bits after the last block are ignored
+        if blocks % 64 != 0 {
+            if let Some(last) = map.bits.last_mut() {
+                *last &= (1 << (blocks % 64)) - 1;
+            }
+        }
+        Ok(map)
+    }
+
+    /// Serializes the block map, see BLOCK_MAP_MAGIC.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let blocks = self.blocks();
+        let mut data = Vec::with_capacity(BLOCK_MAP_HEADER_SIZE + blocks.div_ceil(8) as usize);
+        data.extend_from_slice(BLOCK_MAP_MAGIC);
+        data.extend_from_slice(&self.block_size.to_be_bytes());
+        data.extend_from_slice(&self.disk_size.to_be_bytes());
+        data.extend_from_slice(&blocks.to_be_bytes());
+        for word in self.bits.iter() {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.truncate(BLOCK_MAP_HEADER_SIZE + blocks.div_ceil(8) as usize);
+        data
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    /// Number of blocks covering the disk, the last one can be partial.
+    pub fn blocks(&self) -> u64 {
+        self.disk_size.div_ceil(self.block_size)
+    }
+
+    pub fn set(&mut self, block: u64) {
+        self.bits[(block / 64) as usize] |= 1 << (block % 64);
+    }
+
+    /// Sets the blocks covered by the given ranges, ignoring the parts past
+    /// the end of the disk.
+    pub fn set_ranges(&mut self, ranges: &[VirtualDiskChangeRange]) {
+        let disk_size = self.disk_size;
+        for range in ranges
+            .iter()
+            .filter(|r| r.length > 0 && r.offset < disk_size)
+        {
+            let end = std::cmp::min(range.offset.saturating_add(range.length), disk_size);
+            let first = range.offset / self.block_size;
+            let last = (end - 1) / self.block_size;
+            for block in first..=last {
+                self.set(block);
+            }
+        }
+    }
+
+    pub fn is_set(&self, block: u64) -> bool {
+        self.bits[(block / 64) as usize] & (1 << (block % 64)) != 0
+    }
+
+    /// Number of blocks set.
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|w| w.count_ones() as u64).sum()
+    }
+
+    /// Disk ranges of the blocks set, contiguous blocks are merged.
+    pub fn ranges(&self) -> Vec<VirtualDiskChangeRange> {
+        let mut ranges: Vec<VirtualDiskChangeRange> = Vec::new();
+        for block in (0..self.blocks()).filter(|b| self.is_set(*b)) {
+            let offset = block * self.block_size;
+            let length = std::cmp::min(self.block_size, self.disk_size - offset);
+            match ranges.last_mut() {
+                Some(last) if last.offset + last.length == offset => last.length += length,
+                _ => ranges.push(VirtualDiskChangeRange { offset, length }),
+            }
+        }
+        ranges
+    }
+}
+
+/// Sorts the ranges and extends them to the given alignment, merging the
+/// ones overlapping afterwards. The disk size limits the last range.
+pub fn align_ranges(
+    ranges: &[VirtualDiskChangeRange],
+    alignment: u64,
+    disk_size: u64,
+) -> Vec<VirtualDiskChangeRange> {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|r| r.offset);
+
+    let mut aligned: Vec<VirtualDiskChangeRange> = Vec::new();
+    for range in ranges
+        .iter()
+        .filter(|r| r.length > 0 && r.offset < disk_size)
+    {
+        let start = range.offset / alignment * alignment;
+        let end = std::cmp::min(range.offset.saturating_add(range.length), disk_size);
+        let end = std::cmp::min(end.div_ceil(alignment).saturating_mul(alignment), disk_size);
+        match aligned.last_mut() {
+            Some(last) if start <= last.offset + last.length => {
+                last.length = std::cmp::max(last.length, end - last.offset);
+            }
+            _ => aligned.push(VirtualDiskChangeRange {
+                offset: start,
+                length: end - start,
+            }),
+        }
+    }
+    aligned
+}
+
+/// Reads the concatenated content of the given block aligned ranges,
+/// returning the blocks that are not entirely zero.
+pub fn scan_blocks<R: Read>(
+    source: &mut R,
+    ranges: &[VirtualDiskChangeRange],
+    block_size: u64,
+    disk_size: u64,
+) -> io::Result<BlockMap> {
+    let mut map = BlockMap::new(block_size, disk_size);
+    let mut buf = vec![0u8; block_size as usize];
+    for range in ranges {
+        let mut offset = range.offset;
+        while offset < range.offset + range.length {
+            let length = std::cmp::min(block_size, range.offset + range.length - offset);
+            source.read_exact(&mut buf[..length as usize])?;
+            if buf[..length as usize].iter().any(|b| *b != 0) {
+                map.set(offset / block_size);
+            }
+            offset += length;
+        }
+    }
+    Ok(map)
+}
+
+/// Streams a raw image of a whole disk, with the content of the blocks in
+/// the map and zeros elsewhere.
+pub struct RawImageStream<R: Read> {
+    map: BlockMap,
+    data: R,
+    block: u64,
+    buf: Vec<u8>,
+    buf_pos: usize,
+}
+
+impl<R: Read> RawImageStream<R> {
+    /// data must provide the content of the map's ranges, concatenated.
+    pub fn new(map: BlockMap, data: R) -> RawImageStream<R> {
+        RawImageStream {
+            map,
+            data,
+            block: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+        }
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.map.disk_size()
+    }
+}
+
+impl<R: Read> Read for RawImageStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf.len() {
+            if self.block == self.map.blocks() {
+                return Ok(0);
+            }
+            let offset = self.block * self.map.block_size();
+            let length = std::cmp::min(self.map.block_size(), self.map.disk_size() - offset);
+            self.buf = vec![0u8; length as usize];
+            if self.map.is_set(self.block) {
+                self.data.read_exact(&mut self.buf)?;
+            }
+            self.buf_pos = 0;
+            self.block += 1;
+        }
+
+        let length = std::cmp::min(buf.len(), self.buf.len() - self.buf_pos);
+        buf[..length].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + length]);
+        self.buf_pos += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(offset: u64, length: u64) -> VirtualDiskChangeRange {
+        VirtualDiskChangeRange { offset, length }
+    }
+
+    fn pairs(ranges: &[VirtualDiskChangeRange]) -> Vec<(u64, u64)> {
+        ranges.iter().map(|r| (r.offset, r.length)).collect()
+    }
+
+    #[test]
+    fn block_maps_round_trip() {
+        let map = BlockMap::from_ranges(4096, 65536 + 512, &[range(4096, 1), range(65536, 512)]);
+        let parsed = BlockMap::from_bytes(&map.to_bytes()).unwrap();
+        assert_eq!(parsed.blocks(), 17);
+        assert_eq!(pairs(&parsed.ranges()), vec![(4096, 4096), (65536, 512)]);
+    }
+
+    #[test]
+    fn malformed_headers_are_invalid_data() {
+        let header = |block_size: u64, disk_size: u64, blocks: u64| {
+            let mut data = BLOCK_MAP_MAGIC.to_vec();
+            data.extend_from_slice(&block_size.to_be_bytes());
+            data.extend_from_slice(&disk_size.to_be_bytes());
+            data.extend_from_slice(&blocks.to_be_bytes());
+            data
+        };
+        for data in [
+            header(4096, u64::MAX, u64::MAX / 4096 + 1),
+            header(0, 4096, 1),
+            header(1, u64::MAX, u64::MAX),
+            header(4096, 8192, 2),
+        ]
+        .iter()
+        {
+            let e = BlockMap::from_bytes(data).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn ranges_past_the_disk_are_clipped() {
+        let mut map = BlockMap::new(4096, 8192);
+        map.set_ranges(&[range(4096, u64::MAX), range(65536, 512)]);
+        assert_eq!(pairs(&map.ranges()), vec![(4096, 4096)]);
+        assert_eq!(
+            pairs(&align_ranges(&[range(4096, u64::MAX)], 65536, 8192)),
+            vec![(0, 8192)]
+        );
+    }
+}
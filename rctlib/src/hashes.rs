@@ -0,0 +1,64 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! SHA-256 of the fixed size blocks of a disk, to find the blocks changed
+//! since a backup when its change tracking information is gone.
+
+use ring::digest;
+
+use std::io::{self, Read};
+
+use crate::writer::hex_digest;
+
+/// Hashes of a disk's blocks, e.g. as stored by a previous backup.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockHashes {
+    pub block_size: u64,
+    /// SHA-256 of each block, the last one can be partial. Blocks without a
+    /// hash are always considered changed.
+    pub hashes: Vec<Option<String>>,
+}
+
+pub fn block_hash(data: &[u8]) -> String {
+    hex_digest(&digest::digest(&digest::SHA256, data))
+}
+
+impl BlockHashes {
+    /// Hashes the disk_size bytes read from reader.
+    pub fn from_reader<R: Read>(
+        reader: &mut R,
+        block_size: u64,
+        disk_size: u64,
+    ) -> io::Result<BlockHashes> {
+        let mut hashes = Vec::new();
+        let mut buf = vec![0u8; block_size as usize];
+        let mut offset = 0;
+        while offset < disk_size {
+            let length = std::cmp::min(block_size, disk_size - offset) as usize;
+            reader.read_exact(&mut buf[..length])?;
+            hashes.push(Some(block_hash(&buf[..length])));
+            offset += length as u64;
+        }
+        Ok(BlockHashes { block_size, hashes })
+    }
+
+    /// Whether data, the current content of the given block, differs from
+    /// the hashed one.
+    pub fn is_changed(&self, block: u64, data: &[u8]) -> bool {
+        match self.hashes.get(block as usize) {
+            Some(Some(hash)) => !hash.eq_ignore_ascii_case(&block_hash(data)),
+            _ => true,
+        }
+    }
+}
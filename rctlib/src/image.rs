@@ -0,0 +1,440 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! VirtDisk backend for hosts without the Windows virtual disk API. Raw
+//! images and block devices are read and written in place, VHDX files can
+//! be created and inspected but not attached. Change tracking is answered
+//! from a JSON change list stored next to the image.
+
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::vhdx::{Guid, VhdxReader, VhdxWriter};
+use crate::{
+    RCTInfo, VirtDiskCreateOptions, VirtDiskFormat, VirtDiskType, VirtualDiskChangeRange,
+    VirtualDiskError, ALLOCATED_RANGES_ID, ERROR_ACCESS_DENIED, ERROR_FILE_EXISTS,
+    ERROR_FILE_NOT_FOUND, ERROR_GEN_FAILURE, ERROR_INVALID_PARAMETER, ERROR_NOT_SUPPORTED,
+    ERROR_VHD_INVALID_TYPE, ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION, SECTOR_SIZE,
+};
+
+const VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN: u32 = 0;
+const VIRTUAL_STORAGE_TYPE_DEVICE_VHDX: u32 = 3;
+
+const PROVIDER_SUBTYPE_FIXED: u32 = 2;
+const PROVIDER_SUBTYPE_DYNAMIC: u32 = 3;
+const PROVIDER_SUBTYPE_DIFFERENCING: u32 = 4;
+
+const CHANGES_BATCH_SIZE: usize = 100;
+
+const CHANGE_LIST_SUFFIX: &str = ".rct.json";
+
+// Ranges changed in each snapshot, relative to the previous one, e.g.:
+// {"enabled": true, "snapshots": [
+//     {"id": "snap-1"},
+//     {"id": "snap-2", "changes": [{"offset": 0, "length": 65536}]}]}
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ChangeList {
+    enabled: bool,
+    #[serde(default)]
+    newer_changes: bool,
+    // Stands in for a VM using the image
+    #[serde(default)]
+    loaded: bool,
+    #[serde(default)]
+    snapshots: Vec<ChangeListSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChangeListSnapshot {
+    id: String,
+    #[serde(default)]
+    changes: Vec<VirtualDiskChangeRange>,
+}
+
+fn io_error(e: io::Error) -> VirtualDiskError {
+    VirtualDiskError::new(match e.kind() {
+        io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+        io::ErrorKind::AlreadyExists => ERROR_FILE_EXISTS,
+        io::ErrorKind::PermissionDenied => ERROR_ACCESS_DENIED,
+        io::ErrorKind::InvalidInput => ERROR_INVALID_PARAMETER,
+        io::ErrorKind::InvalidData => ERROR_VHD_INVALID_TYPE,
+        _ => ERROR_GEN_FAILURE,
+    })
+}
+
+fn is_vhdx(file: &mut File) -> io::Result<bool> {
+    let mut signature = [0u8; 8];
+    match file.read_exact(&mut signature) {
+        Ok(()) => Ok(&signature == b"vhdxfile"),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Sorts and merges overlapping or adjacent ranges, limited to the disk size
+fn merge_ranges(
+    mut ranges: Vec<VirtualDiskChangeRange>,
+    virtual_size: u64,
+) -> Vec<VirtualDiskChangeRange> {
+    ranges.sort_by_key(|r| r.offset);
+    let mut merged: Vec<VirtualDiskChangeRange> = Vec::new();
+    for range in ranges {
+        let end = std::cmp::min(range.offset.saturating_add(range.length), virtual_size);
+        if end <= range.offset {
+            continue;
+        }
+        match merged.last_mut() {
+            Some(last) if range.offset <= last.offset + last.length => {
+                last.length = std::cmp::max(last.length, end - last.offset);
+            }
+            _ => merged.push(VirtualDiskChangeRange {
+                offset: range.offset,
+                length: end - range.offset,
+            }),
+        }
+    }
+    merged
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn allocated_ranges(file: &File, size: u64) -> io::Result<Vec<VirtualDiskChangeRange>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // No data past offset
+                Some(libc::ENXIO) => break,
+                // Holes are not supported by the filesystem
+                Some(libc::EINVAL) if offset == 0 => {
+                    return Ok(merge_ranges(
+                        vec![VirtualDiskChangeRange {
+                            offset: 0,
+                            length: size,
+                        }],
+                        size,
+                    ))
+                }
+                _ => return Err(e),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ranges.push(VirtualDiskChangeRange {
+            offset: data as u64,
+            length: (hole - data) as u64,
+        });
+        offset = hole as u64;
+    }
+    Ok(merge_ranges(ranges, size))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn allocated_ranges(_file: &File, size: u64) -> io::Result<Vec<VirtualDiskChangeRange>> {
+    Ok(merge_ranges(
+        vec![VirtualDiskChangeRange {
+            offset: 0,
+            length: size,
+        }],
+        size,
+    ))
+}
+
+enum ImageKind {
+    Raw,
+    Vhdx(VhdxReader),
+}
+
+pub struct VirtDisk {
+    path: String,
+    read_only: bool,
+    kind: ImageKind,
+}
+
+/// Iterates over the ranges changed since an RCT ID within a window of the
+/// disk, in batches of the same size as on Windows. The disk can be borrowed
+/// or owned by the iterator.
+pub struct ChangesIter<D: Borrow<VirtDisk>> {
+    _disk: D,
+    ranges: VecDeque<VirtualDiskChangeRange>,
+}
+
+impl<D: Borrow<VirtDisk>> ChangesIter<D> {
+    pub fn new(
+        disk: D,
+        change_tracking_id: &str,
+        byte_offset: u64,
+        length: u64,
+    ) -> Result<ChangesIter<D>, VirtualDiskError> {
+        let virtual_size = disk.borrow().get_virtual_size()?;
+        let end = match byte_offset.checked_add(length) {
+            Some(end) if end <= virtual_size => end,
+            _ => return Err(VirtualDiskError::new(ERROR_INVALID_PARAMETER)),
+        };
+
+        // Ranges are merged, so clipping them keeps them sorted and disjoint
+        let ranges = disk
+            .borrow()
+            .query_changes(change_tracking_id)?
+            .into_iter()
+            .filter_map(|r| {
+                let start = std::cmp::max(r.offset, byte_offset);
+                let stop = std::cmp::min(r.offset + r.length, end);
+                if start < stop {
+                    Some(VirtualDiskChangeRange {
+                        offset: start,
+                        length: stop - start,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Ok(ChangesIter {
+            _disk: disk,
+            ranges,
+        })
+    }
+}
+
+impl<D: Borrow<VirtDisk>> Iterator for ChangesIter<D> {
+    type Item = Result<Vec<VirtualDiskChangeRange>, VirtualDiskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ranges.is_empty() {
+            return None;
+        }
+        let length = std::cmp::min(CHANGES_BATCH_SIZE, self.ranges.len());
+        Some(Ok(self.ranges.drain(..length).collect()))
+    }
+}
+
+impl VirtDisk {
+    pub fn open(vhd_path: &str, read_only: bool) -> Result<VirtDisk, VirtualDiskError> {
+        let mut file = File::open(vhd_path).map_err(io_error)?;
+        let kind = if is_vhdx(&mut file).map_err(io_error)? {
+            ImageKind::Vhdx(VhdxReader::open(vhd_path).map_err(io_error)?)
+        } else {
+            ImageKind::Raw
+        };
+        Ok(VirtDisk {
+            path: vhd_path.to_string(),
+            read_only,
+            kind,
+        })
+    }
+
+    pub fn create(
+        vhd_path: &str,
+        options: &VirtDiskCreateOptions,
+    ) -> Result<VirtDisk, VirtualDiskError> {
+        match options.format {
+            VirtDiskFormat::Vhdx => {
+                VhdxWriter::create(Path::new(vhd_path), options)
+                    .and_then(|mut writer| writer.flush())
+                    .map_err(io_error)?;
+            }
+            VirtDiskFormat::Raw
+                if options.disk_type != VirtDiskType::Differencing
+                    && options.parent_path.is_none()
+                    && options.virtual_size > 0 =>
+            {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(vhd_path)
+                    .map_err(io_error)?;
+                if options.disk_type == VirtDiskType::Fixed {
+                    // Fixed raw images are fully allocated, dynamic ones sparse
+                    let zeros = vec![0u8; 1024 * 1024];
+                    let mut remaining = options.virtual_size;
+                    while remaining > 0 {
+                        let length = std::cmp::min(remaining, zeros.len() as u64) as usize;
+                        file.write_all(&zeros[..length]).map_err(io_error)?;
+                        remaining -= length as u64;
+                    }
+                }
+                file.set_len(options.virtual_size).map_err(io_error)?;
+                file.sync_all().map_err(io_error)?;
+            }
+            _ => return Err(VirtualDiskError::new(ERROR_INVALID_PARAMETER)),
+        }
+        VirtDisk::open(vhd_path, false)
+    }
+
+    fn change_list_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}{}", self.path, CHANGE_LIST_SUFFIX))
+    }
+
+    fn load_change_list(&self) -> Result<ChangeList, VirtualDiskError> {
+        match File::open(self.change_list_path()) {
+            Ok(file) => serde_json::from_reader(file)
+                .map_err(|_| VirtualDiskError::new(ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ChangeList::default()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    pub fn get_rct_info(&self) -> Result<RCTInfo, VirtualDiskError> {
+        let change_list = self.load_change_list()?;
+        Ok(RCTInfo {
+            enabled: change_list.enabled,
+            newer_changes: change_list.newer_changes,
+            most_recent_id: change_list
+                .snapshots
+                .last()
+                .map_or_else(String::new, |s| s.id.clone()),
+        })
+    }
+
+    /// Enabling change tracking starts a change list with a new ID.
+    pub fn set_rct_info(&mut self, enabled: bool) -> Result<(), VirtualDiskError> {
+        if self.read_only {
+            return Err(VirtualDiskError::new(ERROR_ACCESS_DENIED));
+        }
+        let mut change_list = self.load_change_list()?;
+        change_list.enabled = enabled;
+        if enabled && change_list.snapshots.is_empty() {
+            change_list.snapshots.push(ChangeListSnapshot {
+                id: Guid::random().map_err(io_error)?.to_string(),
+                changes: Vec::new(),
+            });
+        }
+        let file = File::create(self.change_list_path()).map_err(io_error)?;
+        serde_json::to_writer_pretty(file, &change_list)
+            .map_err(|_| VirtualDiskError::new(ERROR_GEN_FAILURE))
+    }
+
+    pub fn get_virtual_size(&self) -> Result<u64, VirtualDiskError> {
+        match self.kind {
+            // Block devices report a zero length in their metadata
+            ImageKind::Raw => File::open(&self.path)
+                .and_then(|mut f| f.seek(SeekFrom::End(0)))
+                .map_err(io_error),
+            ImageKind::Vhdx(ref reader) => Ok(reader.virtual_size()),
+        }
+    }
+
+    /// Size of the disk's blocks, 0 for raw images.
+    pub fn get_block_size(&self) -> Result<u64, VirtualDiskError> {
+        match self.kind {
+            ImageKind::Raw => Ok(0),
+            ImageKind::Vhdx(ref reader) => Ok(reader.block_size()),
+        }
+    }
+
+    /// Size of the logical sectors, raw images use 512 bytes sectors.
+    pub fn get_sector_size(&self) -> Result<u64, VirtualDiskError> {
+        match self.kind {
+            ImageKind::Raw => Ok(SECTOR_SIZE),
+            ImageKind::Vhdx(ref reader) => Ok(reader.logical_sector_size()),
+        }
+    }
+
+    pub fn get_parent_path(&self) -> Result<String, VirtualDiskError> {
+        match self.kind {
+            ImageKind::Vhdx(ref reader) if reader.has_parent() => reader
+                .parent_path()
+                .map(|p| p.to_string_lossy().to_string())
+                .map_err(io_error),
+            _ => Err(VirtualDiskError::new(ERROR_VHD_INVALID_TYPE)),
+        }
+    }
+
+    /// Images are not used by VMs, the change list's loaded flag tells
+    /// whether to consider them in use.
+    pub fn is_loaded(&self) -> Result<bool, VirtualDiskError> {
+        Ok(self.load_change_list()?.loaded)
+    }
+
+    pub fn get_virtual_storage_type(&self) -> Result<u32, VirtualDiskError> {
+        Ok(match self.kind {
+            ImageKind::Raw => VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN,
+            ImageKind::Vhdx(_) => VIRTUAL_STORAGE_TYPE_DEVICE_VHDX,
+        })
+    }
+
+    pub fn get_provider_sub_type(&self) -> Result<u32, VirtualDiskError> {
+        Ok(match self.kind {
+            ImageKind::Raw => PROVIDER_SUBTYPE_FIXED,
+            ImageKind::Vhdx(ref reader) if reader.has_parent() => PROVIDER_SUBTYPE_DIFFERENCING,
+            ImageKind::Vhdx(ref reader) if reader.is_fixed() => PROVIDER_SUBTYPE_FIXED,
+            ImageKind::Vhdx(_) => PROVIDER_SUBTYPE_DYNAMIC,
+        })
+    }
+
+    /// Returns the ranges changed in the snapshots following
+    /// change_tracking_id, or the allocated ranges of raw images for
+    /// ALLOCATED_RANGES_ID.
+    pub fn query_changes(
+        &self,
+        change_tracking_id: &str,
+    ) -> Result<Vec<VirtualDiskChangeRange>, VirtualDiskError> {
+        let virtual_size = self.get_virtual_size()?;
+        if change_tracking_id == ALLOCATED_RANGES_ID {
+            if let ImageKind::Raw = self.kind {
+                return File::open(&self.path)
+                    .and_then(|f| allocated_ranges(&f, virtual_size))
+                    .map_err(io_error);
+            }
+        }
+
+        let missing = || VirtualDiskError::new(ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION);
+        let change_list = self.load_change_list()?;
+        if !change_list.enabled {
+            return Err(missing());
+        }
+        let index = change_list
+            .snapshots
+            .iter()
+            .position(|s| s.id == change_tracking_id)
+            .ok_or_else(missing)?;
+        let changes = change_list
+            .snapshots
+            .into_iter()
+            .skip(index + 1)
+            .flat_map(|s| s.changes)
+            .collect();
+        Ok(merge_ranges(changes, virtual_size))
+    }
+
+    /// The disk must have been opened writable to be attached read-write.
+    /// Only raw images can be attached, as they are their own physical disk.
+    pub fn attach(&self, read_only: bool) -> Result<(), VirtualDiskError> {
+        match self.kind {
+            ImageKind::Raw if !read_only && self.read_only => {
+                Err(VirtualDiskError::new(ERROR_ACCESS_DENIED))
+            }
+            ImageKind::Raw => Ok(()),
+            ImageKind::Vhdx(_) => Err(VirtualDiskError::new(ERROR_NOT_SUPPORTED)),
+        }
+    }
+
+    pub fn get_physical_disk_path(&self) -> Result<String, VirtualDiskError> {
+        match self.kind {
+            ImageKind::Raw => Ok(self.path.clone()),
+            ImageKind::Vhdx(_) => Err(VirtualDiskError::new(ERROR_NOT_SUPPORTED)),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use std::io::{self, Read};
+
+use super::BlockMap;
+
+pub const QCOW2_CLUSTER_SIZE: u64 = 1 << QCOW2_CLUSTER_BITS;
+const QCOW2_CLUSTER_BITS: u32 = 16;
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const QCOW2_VERSION: u32 = 3;
+const QCOW2_HEADER_LENGTH: u32 = 104;
+// 16 bits refcounts
+const QCOW2_REFCOUNT_ORDER: u32 = 4;
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+
+// Entries of each L2 table and refcount block
+const L2_ENTRIES: u64 = QCOW2_CLUSTER_SIZE / 8;
+const REFCOUNT_BLOCK_ENTRIES: u64 = QCOW2_CLUSTER_SIZE / 2;
+
+fn div_round_up(value: u64, divisor: u64) -> u64 {
+    value.div_ceil(divisor)
+}
+
+/// Streams a QCOW2 image of a disk, given the clusters holding data and
+/// their content, without seeking. The metadata, all in front of the data,
+/// is laid out as: header, L1 table, refcount table, refcount blocks and
+/// L2 tables. Clusters not in the map are left unallocated, reading as
+/// zeros.
+pub struct Qcow2Stream<R: Read> {
+    map: BlockMap,
+    data: R,
+    l1_size: u64,
+    l1_clusters: u64,
+    refcount_table_clusters: u64,
+    refcount_blocks: u64,
+    // L1 index and first data cluster of each L2 table
+    l2_tables: Vec<(u64, u64)>,
+    total_clusters: u64,
+    // Next cluster of the image and of the disk to be streamed
+    cluster: u64,
+    disk_cluster: u64,
+    buf: Vec<u8>,
+    buf_pos: usize,
+}
+
+impl<R: Read> Qcow2Stream<R> {
+    /// data must provide the content of the map's ranges, concatenated.
+    pub fn new(map: BlockMap, data: R) -> Qcow2Stream<R> {
+        assert_eq!(map.block_size(), QCOW2_CLUSTER_SIZE);
+
+        let l1_size = div_round_up(map.blocks(), L2_ENTRIES);
+        let l1_clusters = div_round_up(l1_size * 8, QCOW2_CLUSTER_SIZE);
+        let mut l2_tables = Vec::new();
+        let mut data_clusters = 0;
+        for l1_index in 0..l1_size {
+            let first = l1_index * L2_ENTRIES;
+            let last = std::cmp::min(first + L2_ENTRIES, map.blocks());
+            let count = (first..last).filter(|c| map.is_set(*c)).count() as u64;
+            if count > 0 {
+                l2_tables.push((l1_index, data_clusters));
+                data_clusters += count;
+            }
+        }
+
+        // The refcount structures need to cover themselves as well
+        let other_clusters = 1 + l1_clusters + l2_tables.len() as u64 + data_clusters;
+        let mut refcount_table_clusters = 1;
+        let mut refcount_blocks = 1;
+        loop {
+            let total = other_clusters + refcount_table_clusters + refcount_blocks;
+            let blocks = div_round_up(total, REFCOUNT_BLOCK_ENTRIES);
+            let table_clusters = div_round_up(blocks * 8, QCOW2_CLUSTER_SIZE);
+            if blocks == refcount_blocks && table_clusters == refcount_table_clusters {
+                break;
+            }
+            refcount_blocks = blocks;
+            refcount_table_clusters = table_clusters;
+        }
+
+        Qcow2Stream {
+            map,
+            data,
+            l1_size,
+            l1_clusters,
+            refcount_table_clusters,
+            refcount_blocks,
+            l2_tables,
+            total_clusters: other_clusters + refcount_table_clusters + refcount_blocks,
+            cluster: 0,
+            disk_cluster: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+        }
+    }
+
+    /// Size of the whole image.
+    pub fn content_length(&self) -> u64 {
+        self.total_clusters * QCOW2_CLUSTER_SIZE
+    }
+
+    fn refcount_table_start(&self) -> u64 {
+        1 + self.l1_clusters
+    }
+
+    fn refcount_blocks_start(&self) -> u64 {
+        self.refcount_table_start() + self.refcount_table_clusters
+    }
+
+    fn l2_tables_start(&self) -> u64 {
+        self.refcount_blocks_start() + self.refcount_blocks
+    }
+
+    fn data_start(&self) -> u64 {
+        self.l2_tables_start() + self.l2_tables.len() as u64
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(QCOW2_CLUSTER_SIZE as usize);
+        buf.extend_from_slice(QCOW2_MAGIC);
+        buf.extend_from_slice(&QCOW2_VERSION.to_be_bytes());
+        // No backing file
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&QCOW2_CLUSTER_BITS.to_be_bytes());
+        buf.extend_from_slice(&self.map.disk_size().to_be_bytes());
+        // No encryption
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&(self.l1_size as u32).to_be_bytes());
+        // The L1 table follows the header
+        buf.extend_from_slice(&QCOW2_CLUSTER_SIZE.to_be_bytes());
+        buf.extend_from_slice(&(self.refcount_table_start() * QCOW2_CLUSTER_SIZE).to_be_bytes());
+        buf.extend_from_slice(&(self.refcount_table_clusters as u32).to_be_bytes());
+        // No snapshots
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        // Incompatible, compatible and autoclear features
+        buf.extend_from_slice(&[0u8; 24]);
+        buf.extend_from_slice(&QCOW2_REFCOUNT_ORDER.to_be_bytes());
+        buf.extend_from_slice(&QCOW2_HEADER_LENGTH.to_be_bytes());
+        // The header extensions end marker is all zeros
+        buf.resize(QCOW2_CLUSTER_SIZE as usize, 0);
+        buf
+    }
+
+    fn table_cluster<F: FnMut(u64) -> u64>(first: u64, count: u64, mut entry: F) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(QCOW2_CLUSTER_SIZE as usize);
+        for index in first..std::cmp::min(first + L2_ENTRIES, count) {
+            buf.extend_from_slice(&entry(index).to_be_bytes());
+        }
+        buf.resize(QCOW2_CLUSTER_SIZE as usize, 0);
+        buf
+    }
+
+    fn l1_table_cluster(&self, index: u64) -> Vec<u8> {
+        let l2_tables_start = self.l2_tables_start();
+        Self::table_cluster(index * L2_ENTRIES, self.l1_size, |l1_index| {
+            match self.l2_tables.binary_search_by_key(&l1_index, |t| t.0) {
+                Ok(table) => {
+                    ((l2_tables_start + table as u64) * QCOW2_CLUSTER_SIZE) | QCOW_OFLAG_COPIED
+                }
+                Err(_) => 0,
+            }
+        })
+    }
+
+    fn refcount_table_cluster(&self, index: u64) -> Vec<u8> {
+        let refcount_blocks_start = self.refcount_blocks_start();
+        Self::table_cluster(index * L2_ENTRIES, self.refcount_blocks, |block| {
+            (refcount_blocks_start + block) * QCOW2_CLUSTER_SIZE
+        })
+    }
+
+    fn refcount_block(&self, index: u64) -> Vec<u8> {
+        // Every cluster of the image is used once
+        let first = index * REFCOUNT_BLOCK_ENTRIES;
+        let count = std::cmp::min(REFCOUNT_BLOCK_ENTRIES, self.total_clusters - first);
+        let mut buf = 1u16.to_be_bytes().repeat(count as usize);
+        buf.resize(QCOW2_CLUSTER_SIZE as usize, 0);
+        buf
+    }
+
+    fn l2_table(&self, index: usize) -> Vec<u8> {
+        let (l1_index, first_data_cluster) = self.l2_tables[index];
+        let mut data_cluster = self.data_start() + first_data_cluster;
+        let first = l1_index * L2_ENTRIES;
+        Self::table_cluster(first, self.map.blocks(), |cluster| {
+            if self.map.is_set(cluster) {
+                data_cluster += 1;
+                ((data_cluster - 1) * QCOW2_CLUSTER_SIZE) | QCOW_OFLAG_COPIED
+            } else {
+                0
+            }
+        })
+    }
+
+    fn data_cluster(&mut self) -> io::Result<Vec<u8>> {
+        while !self.map.is_set(self.disk_cluster) {
+            self.disk_cluster += 1;
+        }
+        let offset = self.disk_cluster * QCOW2_CLUSTER_SIZE;
+        let length = std::cmp::min(QCOW2_CLUSTER_SIZE, self.map.disk_size() - offset);
+        // The last cluster of the disk can be partial
+        let mut buf = vec![0u8; QCOW2_CLUSTER_SIZE as usize];
+        self.data.read_exact(&mut buf[..length as usize])?;
+        self.disk_cluster += 1;
+        Ok(buf)
+    }
+
+    fn next_cluster(&mut self) -> io::Result<Vec<u8>> {
+        let cluster = self.cluster;
+        if cluster == 0 {
+            Ok(self.header())
+        } else if cluster < self.refcount_table_start() {
+            Ok(self.l1_table_cluster(cluster - 1))
+        } else if cluster < self.refcount_blocks_start() {
+            Ok(self.refcount_table_cluster(cluster - self.refcount_table_start()))
+        } else if cluster < self.l2_tables_start() {
+            Ok(self.refcount_block(cluster - self.refcount_blocks_start()))
+        } else if cluster < self.data_start() {
+            Ok(self.l2_table((cluster - self.l2_tables_start()) as usize))
+        } else {
+            self.data_cluster()
+        }
+    }
+}
+
+impl<R: Read> Read for Qcow2Stream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf.len() {
+            if self.cluster == self.total_clusters {
+                return Ok(0);
+            }
+            self.buf = self.next_cluster()?;
+            self.buf_pos = 0;
+            self.cluster += 1;
+        }
+
+        let length = std::cmp::min(buf.len(), self.buf.len() - self.buf_pos);
+        buf[..length].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + length]);
+        self.buf_pos += length;
+        Ok(length)
+    }
+}
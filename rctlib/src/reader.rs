@@ -0,0 +1,358 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::VirtualDiskChangeRange;
+
+/// Alignment of every disk read, covering both 512 and 4096 bytes sectors.
+pub const READ_ALIGNMENT: usize = 4096;
+pub const DEFAULT_READ_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_READ_AHEAD: usize = 4;
+
+/// Disk side statistics of a RangeReader.
+#[derive(Debug, Default)]
+pub struct ReadStats {
+    bytes: AtomicU64,
+    read_nanos: AtomicU64,
+}
+
+impl ReadStats {
+    /// Bytes read from the disk so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Time spent waiting for disk reads.
+    pub fn read_time(&self) -> Duration {
+        Duration::from_nanos(self.read_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Disk read throughput in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.read_time().as_secs_f64();
+        if secs > 0.0 {
+            self.bytes() as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+// Contiguous ranges are read as a single extent
+fn merge_ranges(ranges: &[VirtualDiskChangeRange]) -> Vec<VirtualDiskChangeRange> {
+    let mut extents: Vec<VirtualDiskChangeRange> = Vec::new();
+    for range in ranges.iter().filter(|r| r.length > 0) {
+        match extents.last_mut() {
+            Some(last)
+                if last.offset.checked_add(last.length) == Some(range.offset)
+                    && last.length.checked_add(range.length).is_some() =>
+            {
+                last.length += range.length;
+            }
+            _ => extents.push(range.clone()),
+        }
+    }
+    extents
+}
+
+fn read_full<R: Read>(source: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match source.read(&mut buf[pos..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Unexpected end of disk",
+                ))
+            }
+            Ok(read) => pos += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn read_extents<R: Read + Seek>(
+    mut source: R,
+    extents: Vec<VirtualDiskChangeRange>,
+    disk_size: u64,
+    read_size: usize,
+    sector_size: u64,
+    sender: SyncSender<io::Result<Vec<u8>>>,
+    stats: Arc<ReadStats>,
+) {
+    for extent in extents {
+        let end = match extent.offset.checked_add(extent.length) {
+            Some(end) if end <= disk_size => end,
+            _ => {
+                let _ = sender.send(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Range {}:{} exceeds the disk size: {}",
+                        extent.offset, extent.length, disk_size
+                    ),
+                )));
+                return;
+            }
+        };
+
+        // The sectors covering the extent are read, the bytes out of it are
+        // dropped. Never read past the end of the disk.
+        let mut offset = extent.offset - extent.offset % sector_size;
+        let aligned_end = std::cmp::min(end.div_ceil(sector_size) * sector_size, disk_size);
+        if let Err(e) = source.seek(SeekFrom::Start(offset)) {
+            let _ = sender.send(Err(e));
+            return;
+        }
+
+        while offset < aligned_end {
+            let length = std::cmp::min(read_size as u64, aligned_end - offset) as usize;
+            let mut buf = vec![0u8; length];
+
+            let start = Instant::now();
+            let result = read_full(&mut source, &mut buf);
+            stats
+                .read_nanos
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+            let failed = result.is_err();
+            if !failed {
+                stats.bytes.fetch_add(length as u64, Ordering::Relaxed);
+                buf.truncate((std::cmp::min(offset + length as u64, end) - offset) as usize);
+                if offset < extent.offset {
+                    buf.drain(..(extent.offset - offset) as usize);
+                }
+            }
+            // Stop if the consumer is gone or on errors
+            if sender.send(result.map(|_| buf)).is_err() || failed {
+                return;
+            }
+            offset += length as u64;
+        }
+    }
+}
+
+/// Reads a list of disk ranges sequentially, using a background thread that
+/// issues large reads ahead of the consumer, overlapping disk IO with sends.
+pub struct RangeReader {
+    receiver: Option<Receiver<io::Result<Vec<u8>>>>,
+    thread: Option<JoinHandle<()>>,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    position: u64,
+    content_length: u64,
+    stats: Arc<ReadStats>,
+}
+
+impl RangeReader {
+    /// read_size must be a multiple of READ_ALIGNMENT, read_ahead is the
+    /// number of read_size buffers that can be queued ahead of the consumer.
+    /// Reads are aligned to sector_size, the disk's logical sector size, so
+    /// ranges don't need to be.
+    pub fn new<R: Read + Seek + Send + 'static>(
+        source: R,
+        ranges: &[VirtualDiskChangeRange],
+        disk_size: u64,
+        read_size: usize,
+        read_ahead: usize,
+        sector_size: u64,
+    ) -> RangeReader {
+        assert!(read_size > 0 && read_size.is_multiple_of(READ_ALIGNMENT));
+        assert!(sector_size > 0 && (read_size as u64).is_multiple_of(sector_size));
+
+        let extents = merge_ranges(ranges);
+        // Ranges exceeding the disk fail when read
+        let content_length = extents
+            .iter()
+            .fold(0u64, |total, x| total.saturating_add(x.length));
+        let stats = Arc::new(ReadStats::default());
+        let (sender, receiver) = sync_channel(read_ahead);
+
+        let thread_stats = stats.clone();
+        let thread = thread::spawn(move || {
+            read_extents(
+                source,
+                extents,
+                disk_size,
+                read_size,
+                sector_size,
+                sender,
+                thread_stats,
+            )
+        });
+
+        RangeReader {
+            receiver: Some(receiver),
+            thread: Some(thread),
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            position: 0,
+            content_length,
+            stats,
+        }
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.content_length
+    }
+
+    pub fn stats(&self) -> &ReadStats {
+        &self.stats
+    }
+
+    // The reader thread stopped, either after the last extent or on errors
+    fn finish(&mut self) -> io::Result<usize> {
+        self.receiver = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                return Err(io::Error::other("The disk reader thread panicked"));
+            }
+        }
+        if self.position < self.content_length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The disk reads stopped after {} of {} bytes",
+                    self.position, self.content_length
+                ),
+            ));
+        }
+        Ok(0)
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk_pos == self.chunk.len() {
+            let next = match self.receiver {
+                Some(ref receiver) => receiver.recv(),
+                None => return self.finish(),
+            };
+            match next {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                }
+                Ok(Err(e)) => {
+                    self.receiver = None;
+                    return Err(e);
+                }
+                Err(_) => return self.finish(),
+            }
+        }
+
+        let length = std::cmp::min(buf.len(), self.chunk.len() - self.chunk_pos);
+        buf[..length].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + length]);
+        self.chunk_pos += length;
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl Drop for RangeReader {
+    fn drop(&mut self) {
+        // Dropping the receiver stops the reader thread at its next send
+        self.receiver = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // Panics on the reads following the first one
+    struct PanickingSource {
+        reads: usize,
+    }
+
+    impl Read for PanickingSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads > 1 {
+                panic!("Disk read failed");
+            }
+            Ok(buf.len())
+        }
+    }
+
+    impl Seek for PanickingSource {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::Start(offset) => Ok(offset),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn read_all(reader: &mut RangeReader) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map(|_| data)
+    }
+
+    fn range(offset: u64, length: u64) -> VirtualDiskChangeRange {
+        VirtualDiskChangeRange { offset, length }
+    }
+
+    #[test]
+    fn unaligned_ranges() {
+        let disk: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+        let ranges = [range(100, 1000), range(1100, 10), range(60000, 5536)];
+        let mut reader = RangeReader::new(Cursor::new(disk.clone()), &ranges, 65536, 4096, 2, 512);
+        assert_eq!(reader.content_length(), 6546);
+        let mut expected = disk[100..1110].to_vec();
+        expected.extend_from_slice(&disk[60000..]);
+        assert_eq!(read_all(&mut reader).unwrap(), expected);
+    }
+
+    #[test]
+    fn overflowing_ranges_are_refused() {
+        let ranges = [range(512, u64::MAX - 511)];
+        let mut reader =
+            RangeReader::new(Cursor::new(vec![0u8; 4096]), &ranges, 4096, 4096, 2, 512);
+        let e = read_all(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        // Contiguous ranges whose merged length would overflow
+        let ranges = [range(0, 512), range(512, u64::MAX)];
+        let mut reader =
+            RangeReader::new(Cursor::new(vec![0u8; 4096]), &ranges, 4096, 4096, 2, 512);
+        let e = read_all(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn thread_panics_are_errors() {
+        let ranges = [range(0, 65536)];
+        let source = PanickingSource { reads: 0 };
+        let mut reader = RangeReader::new(source, &ranges, 65536, 4096, 2, 512);
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4096);
+        let e = reader.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        // Still an error, not the end of the content
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
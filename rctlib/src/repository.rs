@@ -0,0 +1,592 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Content addressed backup repository. Disk data is split in fixed size
+//! chunks, stored once under their SHA-256, and each backup is a manifest
+//! mapping disk offsets to chunks. Incremental backups list only the chunks
+//! changed since their parent backup. The layout is:
+//!
+//! * repository.json: the repository settings
+//! * chunks/<first 2 hex digits>/<SHA-256>: the chunks
+//! * manifests/<name>.json: the backups
+
+use ring::digest;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hashes::{block_hash, BlockHashes};
+use crate::writer::hex_digest;
+
+/// Chunk size of new repositories, dividing all the VHDX block sizes.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+const SETTINGS_FILE: &str = "repository.json";
+const CHUNKS_DIR: &str = "chunks";
+const MANIFESTS_DIR: &str = "manifests";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RepositorySettings {
+    chunk_size: u64,
+}
+
+/// A backup, referring to its parent for the chunks it doesn't list.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub name: String,
+    pub parent: Option<String>,
+    pub disk_size: u64,
+    pub chunk_size: u64,
+    pub created: u64,
+    /// Chunk offsets and SHA-256
+    pub chunks: BTreeMap<u64, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackupSummary {
+    pub name: String,
+    pub chunks: usize,
+    /// Chunks not already in the repository, and their size
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GcSummary {
+    pub chunks_removed: u64,
+    pub bytes_freed: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CheckSummary {
+    pub manifests: usize,
+    pub chunks: u64,
+    /// Chunks not referenced by any manifest, removed by gc
+    pub unreferenced_chunks: u64,
+    pub errors: Vec<String>,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+// Writes a file atomically, so that a crash never leaves partial chunks or
+// manifests behind
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Repository of deduplicated backups. Backups can be written concurrently,
+/// but gc must not run while a backup is in progress, as the new chunks are
+/// not referenced until the backup's manifest is written.
+pub struct ChunkRepository {
+    root: PathBuf,
+    chunk_size: u64,
+}
+
+impl ChunkRepository {
+    pub fn create<P: AsRef<Path>>(root: P, chunk_size: u64) -> io::Result<ChunkRepository> {
+        if !chunk_size.is_power_of_two() || chunk_size < 4096 {
+            return Err(invalid_input(format!("Invalid chunk size: {}", chunk_size)));
+        }
+        let root = root.as_ref();
+        fs::create_dir_all(root.join(CHUNKS_DIR))?;
+        fs::create_dir_all(root.join(MANIFESTS_DIR))?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(root.join(SETTINGS_FILE))?;
+        serde_json::to_writer(&mut file, &RepositorySettings { chunk_size })?;
+        file.sync_all()?;
+        ChunkRepository::open(root)
+    }
+
+    pub fn open<P: AsRef<Path>>(root: P) -> io::Result<ChunkRepository> {
+        let root = root.as_ref();
+        let settings: RepositorySettings =
+            serde_json::from_reader(File::open(root.join(SETTINGS_FILE))?)?;
+        Ok(ChunkRepository {
+            root: root.to_path_buf(),
+            chunk_size: settings.chunk_size,
+        })
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root
+            .join(CHUNKS_DIR)
+            .join(&hash[..std::cmp::min(2, hash.len())])
+            .join(hash)
+    }
+
+    fn manifest_path(&self, name: &str) -> PathBuf {
+        self.root.join(MANIFESTS_DIR).join(format!("{}.json", name))
+    }
+
+    // Stores a chunk unless already present, returning its hash and
+    // whether it is new
+    fn store_chunk(&self, data: &[u8]) -> io::Result<(String, bool)> {
+        let hash = hex_digest(&digest::digest(&digest::SHA256, data));
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, false));
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_file(&path, data)?;
+        Ok((hash, true))
+    }
+
+    fn read_chunk(&self, hash: &str, buf: &mut [u8]) -> io::Result<()> {
+        let data = fs::read(self.chunk_path(hash))?;
+        if data.len() != buf.len() {
+            return Err(invalid_data(format!(
+                "Chunk {} holds {} bytes instead of {}",
+                hash,
+                data.len(),
+                buf.len()
+            )));
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Names of the backups, sorted.
+    pub fn manifests(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.root.join(MANIFESTS_DIR))? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_suffix(".json") {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn manifest(&self, name: &str) -> io::Result<Manifest> {
+        if !is_valid_name(name) {
+            return Err(invalid_input(format!("Invalid backup name: {}", name)));
+        }
+        Ok(serde_json::from_reader(File::open(
+            self.manifest_path(name),
+        )?)?)
+    }
+
+    // The chunks of a backup, including the ones inherited from its parents
+    fn resolve_chunks(&self, name: &str) -> io::Result<BTreeMap<u64, String>> {
+        let mut chunks = BTreeMap::new();
+        let mut visited = HashSet::new();
+        let mut next = Some(name.to_string());
+        while let Some(name) = next {
+            if !visited.insert(name.clone()) {
+                return Err(invalid_data(format!("Backup {} is its own parent", name)));
+            }
+            let manifest = self.manifest(&name)?;
+            for (offset, hash) in manifest.chunks {
+                chunks.entry(offset).or_insert(hash);
+            }
+            next = manifest.parent;
+        }
+        Ok(chunks)
+    }
+
+    /// Hashes of the backup's blocks of chunk_size bytes, without reading
+    /// them: chunks are named after their SHA-256.
+    pub fn block_hashes(&self, name: &str) -> io::Result<BlockHashes> {
+        let manifest = self.manifest(name)?;
+        let chunks = self.resolve_chunks(name)?;
+        // Chunks not stored are zeros
+        let zero_hash = block_hash(&vec![0u8; manifest.chunk_size as usize]);
+        let mut hashes = Vec::new();
+        let mut offset = 0;
+        while offset < manifest.disk_size {
+            let length = std::cmp::min(manifest.chunk_size, manifest.disk_size - offset);
+            let hash = match chunks.get(&offset) {
+                Some(hash) => hash.clone(),
+                None if length == manifest.chunk_size => zero_hash.clone(),
+                None => block_hash(&vec![0u8; length as usize]),
+            };
+            hashes.push(Some(hash));
+            offset += length;
+        }
+        Ok(BlockHashes {
+            block_size: manifest.chunk_size,
+            hashes,
+        })
+    }
+
+    /// Starts a new backup, incremental if a parent backup is given: data
+    /// not written is the parent's.
+    pub fn backup(
+        &self,
+        name: &str,
+        parent: Option<&str>,
+        disk_size: u64,
+    ) -> io::Result<BackupWriter<'_>> {
+        if !is_valid_name(name) {
+            return Err(invalid_input(format!("Invalid backup name: {}", name)));
+        }
+        if self.manifest_path(name).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Backup {} already exists", name),
+            ));
+        }
+        let parent_chunks = match parent {
+            Some(parent) => {
+                let manifest = self.manifest(parent)?;
+                if manifest.disk_size != disk_size || manifest.chunk_size != self.chunk_size {
+                    return Err(invalid_input(format!(
+                        "Backup {} has a different disk or chunk size",
+                        parent
+                    )));
+                }
+                self.resolve_chunks(parent)?
+            }
+            None => BTreeMap::new(),
+        };
+
+        Ok(BackupWriter {
+            repository: self,
+            manifest: Manifest {
+                name: name.to_string(),
+                parent: parent.map(|p| p.to_string()),
+                disk_size,
+                chunk_size: self.chunk_size,
+                created: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                chunks: BTreeMap::new(),
+            },
+            parent_chunks,
+            chunk_offset: None,
+            buf: Vec::new(),
+            position: 0,
+            new_chunks: 0,
+            new_bytes: 0,
+        })
+    }
+
+    /// Reads the full disk content of a backup.
+    pub fn reader(&self, name: &str) -> io::Result<BackupReader<'_>> {
+        let manifest = self.manifest(name)?;
+        Ok(BackupReader {
+            repository: self,
+            disk_size: manifest.disk_size,
+            chunk_size: manifest.chunk_size,
+            chunks: self.resolve_chunks(name)?,
+            position: 0,
+            chunk: None,
+        })
+    }
+
+    /// Deletes a backup, unless other backups refer to it. Its chunks are
+    /// removed by gc.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        self.manifest(name)?;
+        for other in self.manifests()? {
+            if self.manifest(&other)?.parent.as_deref() == Some(name) {
+                return Err(invalid_input(format!(
+                    "Backup {} is the parent of {}",
+                    name, other
+                )));
+            }
+        }
+        fs::remove_file(self.manifest_path(name))
+    }
+
+    fn referenced_chunks(&self) -> io::Result<HashSet<String>> {
+        let mut referenced = HashSet::new();
+        for name in self.manifests()? {
+            referenced.extend(self.manifest(&name)?.chunks.into_iter().map(|c| c.1));
+        }
+        Ok(referenced)
+    }
+
+    // Calls f with the hash and path of every chunk file
+    fn for_each_chunk<F: FnMut(&str, &Path) -> io::Result<()>>(&self, mut f: F) -> io::Result<()> {
+        for dir in fs::read_dir(self.root.join(CHUNKS_DIR))? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                // Leftovers of interrupted writes
+                if file_name.ends_with(".tmp") {
+                    continue;
+                }
+                f(&file_name, &entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the chunks not referenced by any backup.
+    pub fn gc(&self) -> io::Result<GcSummary> {
+        let referenced = self.referenced_chunks()?;
+        let mut summary = GcSummary {
+            chunks_removed: 0,
+            bytes_freed: 0,
+        };
+        self.for_each_chunk(|hash, path| {
+            if !referenced.contains(hash) {
+                summary.bytes_freed += fs::metadata(path)?.len();
+                summary.chunks_removed += 1;
+                fs::remove_file(path)?;
+            }
+            Ok(())
+        })?;
+        Ok(summary)
+    }
+
+    /// Verifies that all the backups' parents and chunks are present, and
+    /// that every chunk matches its hash.
+    pub fn check(&self) -> io::Result<CheckSummary> {
+        let names = self.manifests()?;
+        let mut errors = Vec::new();
+        let mut referenced = HashSet::new();
+        for name in names.iter() {
+            let manifest = match self.manifest(name) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    errors.push(format!("Backup {}: {}", name, e));
+                    continue;
+                }
+            };
+            if let Some(ref parent) = manifest.parent {
+                if !names.contains(parent) {
+                    errors.push(format!("Backup {}: missing parent {}", name, parent));
+                }
+            }
+            for (offset, hash) in manifest.chunks {
+                if !referenced.contains(&hash) && !self.chunk_path(&hash).exists() {
+                    errors.push(format!(
+                        "Backup {}: missing chunk {} at offset {}",
+                        name, hash, offset
+                    ));
+                }
+                referenced.insert(hash);
+            }
+        }
+
+        let mut chunks = 0;
+        let mut unreferenced_chunks = 0;
+        self.for_each_chunk(|hash, path| {
+            chunks += 1;
+            if !referenced.contains(hash) {
+                unreferenced_chunks += 1;
+            }
+            let data = fs::read(path)?;
+            let actual = hex_digest(&digest::digest(&digest::SHA256, &data));
+            if actual != hash {
+                errors.push(format!(
+                    "Chunk {} is corrupted, its SHA-256 is {}",
+                    hash, actual
+                ));
+            }
+            Ok(())
+        })?;
+
+        Ok(CheckSummary {
+            manifests: names.len(),
+            chunks,
+            unreferenced_chunks,
+            errors,
+        })
+    }
+}
+
+/// Writes the data of a new backup, in ascending disk offsets. Chunks of
+/// zeros are omitted unless they replace data of the parent backup.
+pub struct BackupWriter<'a> {
+    repository: &'a ChunkRepository,
+    manifest: Manifest,
+    parent_chunks: BTreeMap<u64, String>,
+    // Chunk being filled, starting with the parent's content
+    chunk_offset: Option<u64>,
+    buf: Vec<u8>,
+    position: u64,
+    new_chunks: u64,
+    new_bytes: u64,
+}
+
+impl<'a> BackupWriter<'a> {
+    fn finish_chunk(&mut self) -> io::Result<()> {
+        let offset = match self.chunk_offset.take() {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        if !self.parent_chunks.contains_key(&offset) && self.buf.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        let (hash, new) = self.repository.store_chunk(&self.buf)?;
+        if new {
+            self.new_chunks += 1;
+            self.new_bytes += self.buf.len() as u64;
+        }
+        self.manifest.chunks.insert(offset, hash);
+        Ok(())
+    }
+
+    fn start_chunk(&mut self, offset: u64) -> io::Result<()> {
+        let length = std::cmp::min(self.manifest.chunk_size, self.manifest.disk_size - offset);
+        self.buf = vec![0u8; length as usize];
+        if let Some(hash) = self.parent_chunks.get(&offset) {
+            self.repository.read_chunk(hash, &mut self.buf)?;
+        }
+        self.chunk_offset = Some(offset);
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset < self.position {
+            return Err(invalid_input(format!(
+                "Writes must be in ascending order, {} follows {}",
+                offset, self.position
+            )));
+        }
+        if offset + data.len() as u64 > self.manifest.disk_size {
+            return Err(invalid_input(format!(
+                "Range {}:{} exceeds the disk size: {}",
+                offset,
+                data.len(),
+                self.manifest.disk_size
+            )));
+        }
+
+        let chunk_size = self.manifest.chunk_size;
+        let mut pos = 0;
+        while pos < data.len() {
+            let disk_offset = offset + pos as u64;
+            let chunk_offset = disk_offset / chunk_size * chunk_size;
+            if self.chunk_offset != Some(chunk_offset) {
+                self.finish_chunk()?;
+                self.start_chunk(chunk_offset)?;
+            }
+            let start = (disk_offset - chunk_offset) as usize;
+            let length = std::cmp::min(self.buf.len() - start, data.len() - pos);
+            self.buf[start..start + length].copy_from_slice(&data[pos..pos + length]);
+            pos += length;
+        }
+        self.position = offset + data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the manifest, making the backup visible.
+    pub fn finish(mut self) -> io::Result<BackupSummary> {
+        self.finish_chunk()?;
+        let path = self.repository.manifest_path(&self.manifest.name);
+        write_file(&path, &serde_json::to_vec(&self.manifest)?)?;
+        Ok(BackupSummary {
+            name: self.manifest.name,
+            chunks: self.manifest.chunks.len(),
+            new_chunks: self.new_chunks,
+            new_bytes: self.new_bytes,
+        })
+    }
+}
+
+/// Reads the disk content of a backup, chunks not present read as zeros.
+pub struct BackupReader<'a> {
+    repository: &'a ChunkRepository,
+    disk_size: u64,
+    chunk_size: u64,
+    chunks: BTreeMap<u64, String>,
+    position: u64,
+    // Last chunk read and its offset
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl<'a> BackupReader<'a> {
+    pub fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    /// Disk offsets of the chunks holding data, e.g. to skip the others
+    /// when restoring to a sparse file.
+    pub fn chunk_offsets(&self) -> Vec<u64> {
+        self.chunks.keys().cloned().collect()
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+}
+
+impl<'a> Read for BackupReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.disk_size {
+            return Ok(0);
+        }
+        let chunk_offset = self.position / self.chunk_size * self.chunk_size;
+        let chunk_length = std::cmp::min(self.chunk_size, self.disk_size - chunk_offset);
+        let start = (self.position - chunk_offset) as usize;
+        let length = std::cmp::min(buf.len(), chunk_length as usize - start);
+
+        match self.chunks.get(&chunk_offset) {
+            Some(hash) => {
+                if self.chunk.as_ref().map(|c| c.0) != Some(chunk_offset) {
+                    let mut chunk = vec![0u8; chunk_length as usize];
+                    self.repository.read_chunk(hash, &mut chunk)?;
+                    self.chunk = Some((chunk_offset, chunk));
+                }
+                let chunk = &self.chunk.as_ref().unwrap().1;
+                buf[..length].copy_from_slice(&chunk[start..start + length]);
+            }
+            None => buf[..length].iter_mut().for_each(|b| *b = 0),
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<'a> Seek for BackupReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(offset) => self.disk_size as i64 + offset,
+        };
+        if position < 0 {
+            return Err(invalid_input("Seek before the start".to_string()));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
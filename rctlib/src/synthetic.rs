@@ -0,0 +1,317 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Synthetic full images: a base image with a series of incremental change
+//! sets applied in order, so that restores don't need to replay the chain.
+
+use ring::digest;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::vhdx::{VhdxReader, VhdxWriter};
+use crate::writer::{hex_digest, SECTOR_SIZE};
+use crate::{VirtDiskCreateOptions, VirtDiskFormat, VirtDiskType, VirtualDiskChangeRange};
+
+const COPY_SIZE: usize = 1024 * 1024;
+
+/// Ranges changed since the previous change set, as returned by the changes
+/// endpoint, and the file holding their content, concatenated.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangeSet {
+    pub ranges: Vec<VirtualDiskChangeRange>,
+    pub data_path: String,
+    /// SHA-256 of the data file, recorded when it was fetched
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SynthesisOptions {
+    /// Raw or VHDX image, VHDX parents are included
+    pub base_path: String,
+    #[serde(default)]
+    pub base_sha256: Option<String>,
+    /// Applied in order, later change sets overwriting earlier ones
+    pub change_sets: Vec<ChangeSet>,
+    pub output_path: String,
+    /// Raw or VHDX, the latter dynamic
+    pub format: VirtDiskFormat,
+    /// Expected SHA-256 of the new image's virtual disk content
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SynthesisSummary {
+    pub change_sets: usize,
+    pub ranges: usize,
+    pub bytes_applied: u64,
+    pub virtual_size: u64,
+    /// SHA-256 of the new image's virtual disk content
+    pub sha256: String,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn check_sha256(name: &str, expected: &Option<String>, actual: &str) -> io::Result<()> {
+    match expected {
+        Some(expected) if !expected.eq_ignore_ascii_case(actual) => Err(invalid_data(format!(
+            "SHA-256 mismatch for {}: expected {}, found {}",
+            name, expected, actual
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Raw images are read directly, VHDX ones with their parents
+enum SourceImage {
+    Raw(File, u64),
+    Vhdx(VhdxReader),
+}
+
+impl SourceImage {
+    fn open(path: &Path) -> io::Result<SourceImage> {
+        let mut file = File::open(path)?;
+        let mut signature = [0u8; 8];
+        let is_vhdx = match file.read_exact(&mut signature) {
+            Ok(()) => &signature == b"vhdxfile",
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        if is_vhdx {
+            return Ok(SourceImage::Vhdx(VhdxReader::open_chain(path)?));
+        }
+        // Block devices report their size only when seeking to the end
+        let size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SourceImage::Raw(file, size))
+    }
+
+    fn virtual_size(&self) -> u64 {
+        match self {
+            SourceImage::Raw(_, size) => *size,
+            SourceImage::Vhdx(reader) => reader.virtual_size(),
+        }
+    }
+
+    fn reader(&mut self) -> &mut dyn Read {
+        match self {
+            SourceImage::Raw(file, _) => file,
+            SourceImage::Vhdx(reader) => reader,
+        }
+    }
+}
+
+enum TargetImage {
+    Raw(File),
+    Vhdx(VhdxWriter),
+}
+
+impl TargetImage {
+    fn create(path: &Path, format: VirtDiskFormat, base: &SourceImage) -> io::Result<TargetImage> {
+        match format {
+            VirtDiskFormat::Raw => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(path)?;
+                // Sparse, only the data written is allocated
+                file.set_len(base.virtual_size())?;
+                Ok(TargetImage::Raw(file))
+            }
+            VirtDiskFormat::Vhdx => {
+                let (block_size, logical_sector_size, physical_sector_size) = match base {
+                    SourceImage::Vhdx(reader) => (
+                        reader.block_size() as u32,
+                        reader.logical_sector_size() as u32,
+                        reader.physical_sector_size() as u32,
+                    ),
+                    SourceImage::Raw(_, _) => (0, 0, 0),
+                };
+                let options = VirtDiskCreateOptions {
+                    format: VirtDiskFormat::Vhdx,
+                    disk_type: VirtDiskType::Dynamic,
+                    virtual_size: base.virtual_size(),
+                    block_size,
+                    logical_sector_size,
+                    physical_sector_size,
+                    parent_path: None,
+                };
+                Ok(TargetImage::Vhdx(VhdxWriter::create(path, &options)?))
+            }
+            VirtDiskFormat::Vhd => Err(invalid_input(
+                "Synthetic full images can be raw or VHDX only".to_string(),
+            )),
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        match self {
+            TargetImage::Raw(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)
+            }
+            TargetImage::Vhdx(writer) => writer.write_at(offset, data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TargetImage::Raw(file) => file.sync_all(),
+            TargetImage::Vhdx(writer) => writer.flush(),
+        }
+    }
+}
+
+// Checks what can be checked before writing anything: range bounds and
+// alignment and the length of the data files
+fn validate_change_sets(change_sets: &[ChangeSet], virtual_size: u64) -> io::Result<()> {
+    for change_set in change_sets {
+        let mut length = 0;
+        for range in change_set.ranges.iter() {
+            if range.offset % SECTOR_SIZE != 0 || range.length % SECTOR_SIZE != 0 {
+                return Err(invalid_input(format!(
+                    "Range {}:{} of {} is not aligned to {} bytes",
+                    range.offset, range.length, change_set.data_path, SECTOR_SIZE
+                )));
+            }
+            if range.offset + range.length > virtual_size {
+                return Err(invalid_input(format!(
+                    "Range {}:{} of {} exceeds the disk size: {}",
+                    range.offset, range.length, change_set.data_path, virtual_size
+                )));
+            }
+            length += range.length;
+        }
+        let data_length = fs::metadata(&change_set.data_path)?.len();
+        if data_length != length {
+            return Err(invalid_data(format!(
+                "{} holds {} bytes, the ranges {}",
+                change_set.data_path, data_length, length
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Copies the base content, leaving blocks of zeros unallocated
+fn copy_base(base: &mut SourceImage, target: &mut TargetImage) -> io::Result<String> {
+    let virtual_size = base.virtual_size();
+    let mut digest = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut offset = 0;
+    while offset < virtual_size {
+        let length = std::cmp::min(COPY_SIZE as u64, virtual_size - offset) as usize;
+        base.reader().read_exact(&mut buf[..length])?;
+        digest.update(&buf[..length]);
+        if buf[..length].iter().any(|b| *b != 0) {
+            target.write_at(offset, &buf[..length])?;
+        }
+        offset += length as u64;
+    }
+    Ok(hex_digest(&digest.finish()))
+}
+
+fn apply_change_set(change_set: &ChangeSet, target: &mut TargetImage) -> io::Result<u64> {
+    let mut data = File::open(&change_set.data_path)?;
+    let mut digest = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; COPY_SIZE];
+    let mut bytes_applied = 0;
+    for range in change_set.ranges.iter() {
+        let mut offset = range.offset;
+        while offset < range.offset + range.length {
+            let length = std::cmp::min(COPY_SIZE as u64, range.offset + range.length - offset);
+            let chunk = &mut buf[..length as usize];
+            data.read_exact(chunk)?;
+            digest.update(chunk);
+            target.write_at(offset, chunk)?;
+            offset += length;
+        }
+        bytes_applied += range.length;
+    }
+    check_sha256(
+        &change_set.data_path,
+        &change_set.sha256,
+        &hex_digest(&digest.finish()),
+    )?;
+    Ok(bytes_applied)
+}
+
+fn image_sha256(path: &Path) -> io::Result<String> {
+    let mut image = SourceImage::open(path)?;
+    let mut digest = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; COPY_SIZE];
+    loop {
+        let read = image.reader().read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buf[..read]);
+    }
+    Ok(hex_digest(&digest.finish()))
+}
+
+fn build(
+    options: &SynthesisOptions,
+    base: &mut SourceImage,
+    mut target: TargetImage,
+    output_path: &Path,
+) -> io::Result<SynthesisSummary> {
+    let base_sha256 = copy_base(base, &mut target)?;
+    check_sha256(&options.base_path, &options.base_sha256, &base_sha256)?;
+
+    let mut bytes_applied = 0;
+    for change_set in options.change_sets.iter() {
+        bytes_applied += apply_change_set(change_set, &mut target)?;
+    }
+    target.flush()?;
+    drop(target);
+
+    // The new image is read back, verifying what was actually written
+    let sha256 = image_sha256(output_path)?;
+    check_sha256(&options.output_path, &options.sha256, &sha256)?;
+
+    Ok(SynthesisSummary {
+        change_sets: options.change_sets.len(),
+        ranges: options.change_sets.iter().map(|c| c.ranges.len()).sum(),
+        bytes_applied,
+        virtual_size: base.virtual_size(),
+        sha256,
+    })
+}
+
+/// Builds a new full image from a base image and the given change sets,
+/// verifying the recorded checksums. The output file must not exist, and
+/// it is removed if any step fails.
+pub fn synthesize_full(options: &SynthesisOptions) -> io::Result<SynthesisSummary> {
+    let mut base = SourceImage::open(Path::new(&options.base_path))?;
+    validate_change_sets(&options.change_sets, base.virtual_size())?;
+
+    let output_path = Path::new(&options.output_path);
+    let target = TargetImage::create(output_path, options.format, &base)?;
+    let result = build(options, &mut base, target, output_path);
+    if result.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    result
+}
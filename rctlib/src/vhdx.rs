@@ -0,0 +1,1628 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Native VHDX support, following the MS-VHDX specification, so that disks
+//! can be built and read without the Windows virtual disk API.
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{BlockMap, VirtDiskCreateOptions, VirtDiskFormat, VirtDiskType};
+
+const MIB: u64 = 1024 * 1024;
+
+pub const VHDX_DEFAULT_BLOCK_SIZE: u32 = 32 * 1024 * 1024;
+const DEFAULT_LOGICAL_SECTOR_SIZE: u32 = 512;
+const DEFAULT_PHYSICAL_SECTOR_SIZE: u32 = 4096;
+const MAX_VIRTUAL_SIZE: u64 = 64 * 1024 * 1024 * MIB;
+
+const FILE_SIGNATURE: &[u8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8] = b"head";
+const REGION_TABLE_SIGNATURE: &[u8] = b"regi";
+const METADATA_SIGNATURE: &[u8] = b"metadata";
+const CREATOR: &str = "rctlib";
+
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const HEADER_SIZE: usize = 4096;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * 1024, 256 * 1024];
+const REGION_TABLE_SIZE: usize = 64 * 1024;
+// Fixed layout of the files created here, all regions are 1 MiB aligned
+const LOG_OFFSET: u64 = MIB;
+const LOG_LENGTH: u64 = MIB;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LENGTH: u64 = MIB;
+const BAT_OFFSET: u64 = 3 * MIB;
+const METADATA_ITEMS_OFFSET: usize = 64 * 1024;
+
+const BAT_REGION: Guid = guid(
+    0x2DC27766,
+    0xF623,
+    0x4200,
+    [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08],
+);
+const METADATA_REGION: Guid = guid(
+    0x8B7CA206,
+    0x4790,
+    0x4B9A,
+    [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E],
+);
+const FILE_PARAMETERS_ITEM: Guid = guid(
+    0xCAA16737,
+    0xFA36,
+    0x4D43,
+    [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B],
+);
+const VIRTUAL_DISK_SIZE_ITEM: Guid = guid(
+    0x2FA54224,
+    0xCD1B,
+    0x4876,
+    [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8],
+);
+const VIRTUAL_DISK_ID_ITEM: Guid = guid(
+    0xBECA12AB,
+    0xB2E6,
+    0x4523,
+    [0x93, 0xEF, 0xC3, 0x09, 0xE0, 0x00, 0xC7, 0x46],
+);
+const LOGICAL_SECTOR_SIZE_ITEM: Guid = guid(
+    0x8141BF1D,
+    0xA96F,
+    0x4709,
+    [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F],
+);
+const PHYSICAL_SECTOR_SIZE_ITEM: Guid = guid(
+    0xCDA348C7,
+    0x445D,
+    0x4471,
+    [0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56],
+);
+const PARENT_LOCATOR_ITEM: Guid = guid(
+    0xA8D35F2D,
+    0xB30B,
+    0x454D,
+    [0xAB, 0xF7, 0xD3, 0xD8, 0x48, 0x34, 0xAB, 0x0C],
+);
+const VHDX_PARENT_LOCATOR_TYPE: Guid = guid(
+    0xB04AEFB7,
+    0xD19E,
+    0x4A81,
+    [0xB7, 0x89, 0x25, 0xB8, 0xE9, 0x44, 0x59, 0x13],
+);
+
+// Metadata table entry flags
+const METADATA_IS_VIRTUAL_DISK: u32 = 2;
+const METADATA_IS_REQUIRED: u32 = 4;
+
+// File parameters flags
+const LEAVE_BLOCKS_ALLOCATED: u32 = 1;
+const HAS_PARENT: u32 = 2;
+
+// BAT entry states
+const PAYLOAD_BLOCK_ZERO: u64 = 2;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+const SB_BLOCK_PRESENT: u64 = 6;
+const BAT_STATE_MASK: u64 = 7;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F63B78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut value = [0u8; 2];
+    value.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_le_bytes(value)
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn get_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+fn put(buf: &mut [u8], offset: usize, value: &[u8]) {
+    buf[offset..offset + value.len()].copy_from_slice(value);
+}
+
+fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .flat_map(|c| c.to_le_bytes().to_vec())
+        .collect()
+}
+
+fn utf16_string(buf: &[u8]) -> io::Result<String> {
+    let chars: Vec<u16> = buf
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&chars).map_err(|_| invalid_data("Invalid UTF-16 string"))
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+/// GUID in its on disk, mixed endian, representation.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid([u8; 16]);
+
+const fn guid(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+    let a = data1.to_le_bytes();
+    let b = data2.to_le_bytes();
+    let c = data3.to_le_bytes();
+    Guid([
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+        data4[4], data4[5], data4[6], data4[7],
+    ])
+}
+
+impl Guid {
+    pub(crate) fn random() -> io::Result<Guid> {
+        let mut bytes = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| io::Error::other("Unable to generate a GUID"))?;
+        // Version 4, variant 1
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Guid(bytes))
+    }
+
+    fn from_slice(buf: &[u8]) -> Guid {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&buf[..16]);
+        Guid(bytes)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    /// Parses the "{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}" format.
+    pub fn parse(s: &str) -> Option<Guid> {
+        let hex: String = s
+            .trim_matches(|c| c == '{' || c == '}')
+            .chars()
+            .filter(|c| *c != '-')
+            .collect();
+        if hex.len() != 32 {
+            return None;
+        }
+        let mut v = [0u8; 16];
+        for (i, b) in v.iter_mut().enumerate() {
+            *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(guid(
+            u32::from_be_bytes([v[0], v[1], v[2], v[3]]),
+            u16::from_be_bytes([v[4], v[5]]),
+            u16::from_be_bytes([v[6], v[7]]),
+            [v[8], v[9], v[10], v[11], v[12], v[13], v[14], v[15]],
+        ))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{{{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            get_u32(b, 0),
+            get_u16(b, 4),
+            get_u16(b, 6),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone)]
+struct Header {
+    sequence_number: u64,
+    file_write_guid: Guid,
+    data_write_guid: Guid,
+    log_guid: Guid,
+    log_length: u32,
+    log_offset: u64,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        put(&mut buf, 0, HEADER_SIGNATURE);
+        put(&mut buf, 8, &self.sequence_number.to_le_bytes());
+        put(&mut buf, 16, &self.file_write_guid.0);
+        put(&mut buf, 32, &self.data_write_guid.0);
+        put(&mut buf, 48, &self.log_guid.0);
+        // Log version 0, version 1
+        put(&mut buf, 66, &1u16.to_le_bytes());
+        put(&mut buf, 68, &self.log_length.to_le_bytes());
+        put(&mut buf, 72, &self.log_offset.to_le_bytes());
+        let checksum = crc32c(&buf);
+        put(&mut buf, 4, &checksum.to_le_bytes());
+        buf
+    }
+
+    fn parse(buf: &[u8]) -> Option<Header> {
+        let mut data = buf.to_vec();
+        put(&mut data, 4, &[0u8; 4]);
+        if &buf[..4] != HEADER_SIGNATURE
+            || get_u32(buf, 4) != crc32c(&data)
+            || get_u16(buf, 66) != 1
+        {
+            return None;
+        }
+        Some(Header {
+            sequence_number: get_u64(buf, 8),
+            file_write_guid: Guid::from_slice(&buf[16..]),
+            data_write_guid: Guid::from_slice(&buf[32..]),
+            log_guid: Guid::from_slice(&buf[48..]),
+            log_length: get_u32(buf, 68),
+            log_offset: get_u64(buf, 72),
+        })
+    }
+}
+
+// Block and sector geometry, which determines the BAT layout
+#[derive(Clone, Copy)]
+struct Layout {
+    virtual_size: u64,
+    block_size: u64,
+    logical_sector_size: u64,
+    physical_sector_size: u64,
+    has_parent: bool,
+    // Payload blocks covered by each sector bitmap block
+    chunk_ratio: u64,
+}
+
+impl Layout {
+    fn new(
+        virtual_size: u64,
+        block_size: u64,
+        logical_sector_size: u64,
+        physical_sector_size: u64,
+        has_parent: bool,
+    ) -> Layout {
+        Layout {
+            virtual_size,
+            block_size,
+            logical_sector_size,
+            physical_sector_size,
+            has_parent,
+            chunk_ratio: (1u64 << 23) * logical_sector_size / block_size,
+        }
+    }
+
+    fn payload_blocks(&self) -> u64 {
+        self.virtual_size.div_ceil(self.block_size)
+    }
+
+    fn bat_entries(&self) -> u64 {
+        let payload_blocks = self.payload_blocks();
+        if self.has_parent {
+            let bitmap_blocks = payload_blocks.div_ceil(self.chunk_ratio);
+            bitmap_blocks * (self.chunk_ratio + 1)
+        } else if payload_blocks == 0 {
+            0
+        } else {
+            payload_blocks + (payload_blocks - 1) / self.chunk_ratio
+        }
+    }
+
+    fn bat_length(&self) -> u64 {
+        (self.bat_entries() * 8).div_ceil(MIB) * MIB
+    }
+
+    fn payload_index(&self, block: u64) -> usize {
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    fn bitmap_index(&self, block: u64) -> usize {
+        let chunk = block / self.chunk_ratio;
+        (chunk * (self.chunk_ratio + 1) + self.chunk_ratio) as usize
+    }
+
+    // Offset of the sector in its sector bitmap block, in bits
+    fn bitmap_bit(&self, offset: u64) -> u64 {
+        let chunk_size = self.chunk_ratio * self.block_size;
+        (offset % chunk_size) / self.logical_sector_size
+    }
+}
+
+fn bat_entry(state: u64, file_offset: u64) -> u64 {
+    state | (file_offset / MIB) << 20
+}
+
+fn bat_entry_offset(entry: u64) -> u64 {
+    (entry >> 20) * MIB
+}
+
+fn file_identifier() -> Vec<u8> {
+    let mut buf = vec![0u8; 64 * 1024];
+    put(&mut buf, 0, FILE_SIGNATURE);
+    put(&mut buf, 8, &utf16_bytes(CREATOR));
+    buf
+}
+
+fn region_table(bat_length: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; REGION_TABLE_SIZE];
+    put(&mut buf, 0, REGION_TABLE_SIGNATURE);
+    put(&mut buf, 8, &2u32.to_le_bytes());
+    let regions = [
+        (BAT_REGION, BAT_OFFSET, bat_length),
+        (METADATA_REGION, METADATA_OFFSET, METADATA_LENGTH),
+    ];
+    for (i, (guid, offset, length)) in regions.iter().enumerate() {
+        let entry = 16 + i * 32;
+        put(&mut buf, entry, &guid.0);
+        put(&mut buf, entry + 16, &offset.to_le_bytes());
+        put(&mut buf, entry + 24, &(*length as u32).to_le_bytes());
+        // Required
+        put(&mut buf, entry + 28, &1u32.to_le_bytes());
+    }
+    let checksum = crc32c(&buf);
+    put(&mut buf, 4, &checksum.to_le_bytes());
+    buf
+}
+
+// Returns the offset and length of the BAT and metadata regions
+fn parse_region_table(buf: &[u8]) -> io::Result<((u64, u64), (u64, u64))> {
+    let mut data = buf.to_vec();
+    put(&mut data, 4, &[0u8; 4]);
+    if &buf[..4] != REGION_TABLE_SIGNATURE || get_u32(buf, 4) != crc32c(&data) {
+        return Err(invalid_data("Invalid VHDX region table"));
+    }
+
+    let count = get_u32(buf, 8) as usize;
+    if count > 2047 {
+        return Err(invalid_data("Invalid VHDX region table"));
+    }
+    let mut bat = None;
+    let mut metadata = None;
+    for i in 0..count {
+        let entry = 16 + i * 32;
+        let region = (get_u64(buf, entry + 16), get_u32(buf, entry + 24) as u64);
+        match Guid::from_slice(&buf[entry..]) {
+            BAT_REGION => bat = Some(region),
+            METADATA_REGION => metadata = Some(region),
+            _ if get_u32(buf, entry + 28) & 1 != 0 => {
+                return Err(invalid_data("Unsupported required VHDX region"))
+            }
+            _ => {}
+        }
+    }
+    match (bat, metadata) {
+        (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+        _ => Err(invalid_data("Missing VHDX BAT or metadata region")),
+    }
+}
+
+/// Key / value pairs locating the parent of a differencing disk.
+#[derive(Debug, Clone, Default)]
+pub struct ParentLocator {
+    pub entries: Vec<(String, String)>,
+}
+
+impl ParentLocator {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let strings_offset = 20 + self.entries.len() * 12;
+        let mut buf = vec![0u8; strings_offset];
+        put(&mut buf, 0, &VHDX_PARENT_LOCATOR_TYPE.0);
+        put(&mut buf, 18, &(self.entries.len() as u16).to_le_bytes());
+        for (i, (key, value)) in self.entries.iter().enumerate() {
+            let key = utf16_bytes(key);
+            let value = utf16_bytes(value);
+            let entry = 20 + i * 12;
+            let key_offset = buf.len();
+            put(&mut buf, entry, &(key_offset as u32).to_le_bytes());
+            put(
+                &mut buf,
+                entry + 4,
+                &((key_offset + key.len()) as u32).to_le_bytes(),
+            );
+            put(&mut buf, entry + 8, &(key.len() as u16).to_le_bytes());
+            put(&mut buf, entry + 10, &(value.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&key);
+            buf.extend_from_slice(&value);
+        }
+        buf
+    }
+
+    fn parse(buf: &[u8]) -> io::Result<ParentLocator> {
+        let invalid = || invalid_data("Invalid VHDX parent locator");
+        if buf.len() < 20 || Guid::from_slice(buf) != VHDX_PARENT_LOCATOR_TYPE {
+            return Err(invalid());
+        }
+        let count = get_u16(buf, 18) as usize;
+        let mut entries = Vec::new();
+        for i in 0..count {
+            let entry = 20 + i * 12;
+            if entry + 12 > buf.len() {
+                return Err(invalid());
+            }
+            let string = |offset: usize, length: usize| {
+                buf.get(offset..offset + length)
+                    .ok_or_else(invalid)
+                    .and_then(utf16_string)
+            };
+            let key = string(
+                get_u32(buf, entry) as usize,
+                get_u16(buf, entry + 8) as usize,
+            )?;
+            let value = string(
+                get_u32(buf, entry + 4) as usize,
+                get_u16(buf, entry + 10) as usize,
+            )?;
+            entries.push((key, value));
+        }
+        Ok(ParentLocator { entries })
+    }
+}
+
+fn metadata_region(
+    layout: &Layout,
+    leave_blocks_allocated: bool,
+    disk_id: Guid,
+    parent_locator: Option<&ParentLocator>,
+) -> Vec<u8> {
+    let mut flags = 0;
+    if leave_blocks_allocated {
+        flags |= LEAVE_BLOCKS_ALLOCATED;
+    }
+    if layout.has_parent {
+        flags |= HAS_PARENT;
+    }
+    let mut file_parameters = (layout.block_size as u32).to_le_bytes().to_vec();
+    file_parameters.extend_from_slice(&flags.to_le_bytes());
+
+    let virtual_disk = METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED;
+    let mut items = vec![
+        (FILE_PARAMETERS_ITEM, METADATA_IS_REQUIRED, file_parameters),
+        (
+            VIRTUAL_DISK_SIZE_ITEM,
+            virtual_disk,
+            layout.virtual_size.to_le_bytes().to_vec(),
+        ),
+        (VIRTUAL_DISK_ID_ITEM, virtual_disk, disk_id.0.to_vec()),
+        (
+            LOGICAL_SECTOR_SIZE_ITEM,
+            virtual_disk,
+            (layout.logical_sector_size as u32).to_le_bytes().to_vec(),
+        ),
+        (
+            PHYSICAL_SECTOR_SIZE_ITEM,
+            virtual_disk,
+            (layout.physical_sector_size as u32).to_le_bytes().to_vec(),
+        ),
+    ];
+    if let Some(parent_locator) = parent_locator {
+        items.push((
+            PARENT_LOCATOR_ITEM,
+            METADATA_IS_REQUIRED,
+            parent_locator.to_bytes(),
+        ));
+    }
+
+    let mut buf = vec![0u8; METADATA_ITEMS_OFFSET];
+    put(&mut buf, 0, METADATA_SIGNATURE);
+    put(&mut buf, 10, &(items.len() as u16).to_le_bytes());
+    for (i, (item, flags, data)) in items.iter().enumerate() {
+        let entry = 32 + i * 32;
+        let offset = buf.len();
+        put(&mut buf, entry, &item.0);
+        put(&mut buf, entry + 16, &(offset as u32).to_le_bytes());
+        put(&mut buf, entry + 20, &(data.len() as u32).to_le_bytes());
+        put(&mut buf, entry + 24, &flags.to_le_bytes());
+        buf.extend_from_slice(data);
+        // Keep the items 8 bytes aligned
+        buf.resize(buf.len().div_ceil(8) * 8, 0);
+    }
+    buf
+}
+
+struct Metadata {
+    layout: Layout,
+    leave_blocks_allocated: bool,
+    parent_locator: Option<ParentLocator>,
+}
+
+fn parse_metadata(buf: &[u8]) -> io::Result<Metadata> {
+    if &buf[..8] != METADATA_SIGNATURE {
+        return Err(invalid_data("Invalid VHDX metadata region"));
+    }
+
+    let mut file_parameters = None;
+    let mut virtual_size = None;
+    let mut logical_sector_size = None;
+    let mut physical_sector_size = None;
+    let mut parent_locator = None;
+    let count = get_u16(buf, 10) as usize;
+    for i in 0..count {
+        let entry = 32 + i * 32;
+        if entry + 32 > METADATA_ITEMS_OFFSET {
+            return Err(invalid_data("Invalid VHDX metadata region"));
+        }
+        let offset = get_u32(buf, entry + 16) as usize;
+        let length = get_u32(buf, entry + 20) as usize;
+        let data = buf
+            .get(offset..offset + length)
+            .ok_or_else(|| invalid_data("Invalid VHDX metadata item"))?;
+        let item = Guid::from_slice(&buf[entry..]);
+        let value_u32 = || data.get(..4).map(|d| get_u32(d, 0) as u64);
+        match item {
+            FILE_PARAMETERS_ITEM if length >= 8 => {
+                file_parameters = Some((get_u32(data, 0) as u64, get_u32(data, 4)))
+            }
+            VIRTUAL_DISK_SIZE_ITEM if length >= 8 => virtual_size = Some(get_u64(data, 0)),
+            LOGICAL_SECTOR_SIZE_ITEM => logical_sector_size = value_u32(),
+            PHYSICAL_SECTOR_SIZE_ITEM => physical_sector_size = value_u32(),
+            PARENT_LOCATOR_ITEM => parent_locator = Some(ParentLocator::parse(data)?),
+            VIRTUAL_DISK_ID_ITEM => {}
+            _ if get_u32(buf, entry + 24) & METADATA_IS_REQUIRED != 0 => {
+                return Err(invalid_data("Unsupported required VHDX metadata item"))
+            }
+            _ => {}
+        }
+    }
+
+    match (
+        file_parameters,
+        virtual_size,
+        logical_sector_size,
+        physical_sector_size,
+    ) {
+        (
+            Some((block_size, flags)),
+            Some(virtual_size),
+            Some(logical_sector_size),
+            Some(physical_sector_size),
+        ) if block_size >= MIB
+            && block_size.is_power_of_two()
+            && (logical_sector_size == 512 || logical_sector_size == 4096) =>
+        {
+            Ok(Metadata {
+                layout: Layout::new(
+                    virtual_size,
+                    block_size,
+                    logical_sector_size,
+                    physical_sector_size,
+                    flags & HAS_PARENT != 0,
+                ),
+                leave_blocks_allocated: flags & LEAVE_BLOCKS_ALLOCATED != 0,
+                parent_locator,
+            })
+        }
+        _ => Err(invalid_data("Missing or invalid VHDX metadata items")),
+    }
+}
+
+// Writes both headers in turn, so that a valid one is always available
+fn update_headers(file: &mut File, header: &mut Header) -> io::Result<()> {
+    for _ in 0..2 {
+        header.sequence_number += 1;
+        let offset = HEADER_OFFSETS[(header.sequence_number % 2) as usize];
+        write_at(file, offset, &header.to_bytes())?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Builds a dynamic, fixed or differencing VHDX file. Payload blocks are
+/// allocated on first write, and for differencing disks the sector bitmaps
+/// track which sectors are present in this layer. The log is never used:
+/// the BAT and bitmaps are updated only after the data they refer to is
+/// written, and the headers are updated one at a time.
+pub struct VhdxWriter {
+    file: File,
+    layout: Layout,
+    bat: Vec<u64>,
+    file_end: u64,
+}
+
+impl VhdxWriter {
+    pub fn create(path: &Path, options: &VirtDiskCreateOptions) -> io::Result<VhdxWriter> {
+        if options.format != VirtDiskFormat::Vhdx {
+            return Err(invalid_input(
+                "Only VHDX disks can be created natively".to_string(),
+            ));
+        }
+
+        let has_parent = options.disk_type == VirtDiskType::Differencing;
+        let parent = match options.parent_path {
+            Some(ref parent_path) if has_parent => Some(VhdxReader::open(parent_path)?),
+            None if !has_parent => None,
+            _ => {
+                return Err(invalid_input(
+                    "A parent path is required for differencing disks only".to_string(),
+                ))
+            }
+        };
+
+        let or_default = |value: u32, parent_value: Option<u64>, default: u32| {
+            if value > 0 {
+                value as u64
+            } else {
+                parent_value.unwrap_or(default as u64)
+            }
+        };
+        let virtual_size = match parent {
+            Some(ref parent) if options.virtual_size == 0 => parent.virtual_size(),
+            _ => options.virtual_size,
+        };
+        let block_size = or_default(options.block_size, None, VHDX_DEFAULT_BLOCK_SIZE);
+        let logical_sector_size = or_default(
+            options.logical_sector_size,
+            parent.as_ref().map(|p| p.logical_sector_size()),
+            DEFAULT_LOGICAL_SECTOR_SIZE,
+        );
+        let physical_sector_size = or_default(
+            options.physical_sector_size,
+            parent.as_ref().map(|p| p.layout.physical_sector_size),
+            DEFAULT_PHYSICAL_SECTOR_SIZE,
+        );
+
+        if !block_size.is_power_of_two() || !(MIB..=256 * MIB).contains(&block_size) {
+            return Err(invalid_input(format!("Invalid block size: {}", block_size)));
+        }
+        for sector_size in [logical_sector_size, physical_sector_size].iter() {
+            if *sector_size != 512 && *sector_size != 4096 {
+                return Err(invalid_input(format!(
+                    "Invalid sector size: {}",
+                    sector_size
+                )));
+            }
+        }
+        if virtual_size == 0
+            || virtual_size > MAX_VIRTUAL_SIZE
+            || virtual_size % logical_sector_size != 0
+        {
+            return Err(invalid_input(format!(
+                "Invalid virtual size: {}",
+                virtual_size
+            )));
+        }
+        if let Some(ref parent) = parent {
+            if parent.virtual_size() != virtual_size
+                || parent.logical_sector_size() != logical_sector_size
+            {
+                return Err(invalid_input(
+                    "Differencing disks must match the size and sector size of the parent"
+                        .to_string(),
+                ));
+            }
+        }
+
+        let layout = Layout::new(
+            virtual_size,
+            block_size,
+            logical_sector_size,
+            physical_sector_size,
+            has_parent,
+        );
+        let parent_locator = match options.parent_path {
+            Some(ref parent_path) if has_parent => Some(parent_locator(
+                path,
+                Path::new(parent_path),
+                parent.as_ref().unwrap().data_write_guid,
+            )?),
+            _ => None,
+        };
+        let is_fixed = options.disk_type == VirtDiskType::Fixed;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        let bat_length = layout.bat_length();
+        let mut writer = VhdxWriter {
+            file: file.try_clone()?,
+            layout,
+            bat: vec![0u64; layout.bat_entries() as usize],
+            file_end: BAT_OFFSET + bat_length,
+        };
+
+        write_at(&mut file, 0, &file_identifier())?;
+        for offset in REGION_TABLE_OFFSETS.iter() {
+            write_at(&mut file, *offset, &region_table(bat_length))?;
+        }
+        write_at(
+            &mut file,
+            METADATA_OFFSET,
+            &metadata_region(&layout, is_fixed, Guid::random()?, parent_locator.as_ref()),
+        )?;
+
+        // Fixed disks have all their blocks allocated upfront
+        if is_fixed {
+            for block in 0..layout.payload_blocks() {
+                let index = layout.payload_index(block);
+                writer.bat[index] = bat_entry(PAYLOAD_BLOCK_FULLY_PRESENT, writer.file_end);
+                writer.file_end += block_size;
+            }
+        }
+        writer.file.set_len(writer.file_end)?;
+        let bat: Vec<u8> = writer
+            .bat
+            .iter()
+            .flat_map(|e| e.to_le_bytes().to_vec())
+            .collect();
+        write_at(&mut file, BAT_OFFSET, &bat)?;
+        file.sync_data()?;
+
+        let mut header = Header {
+            sequence_number: 0,
+            file_write_guid: Guid::random()?,
+            data_write_guid: Guid::random()?,
+            log_guid: Guid::default(),
+            log_length: LOG_LENGTH as u32,
+            log_offset: LOG_OFFSET,
+        };
+        update_headers(&mut file, &mut header)?;
+        Ok(writer)
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.layout.virtual_size
+    }
+
+    pub fn logical_sector_size(&self) -> u64 {
+        self.layout.logical_sector_size
+    }
+
+    fn set_bat_entry(&mut self, index: usize, entry: u64) -> io::Result<()> {
+        self.bat[index] = entry;
+        write_at(
+            &mut self.file,
+            BAT_OFFSET + index as u64 * 8,
+            &entry.to_le_bytes(),
+        )
+    }
+
+    // Appends a zeroed block to the file, returning its offset
+    fn allocate(&mut self, size: u64) -> io::Result<u64> {
+        let offset = self.file_end;
+        self.file_end += size;
+        self.file.set_len(self.file_end)?;
+        Ok(offset)
+    }
+
+    // Marks the sectors of a range within a block as present in this layer
+    fn set_sectors_present(&mut self, block: u64, offset: u64, length: u64) -> io::Result<()> {
+        let bitmap_index = self.layout.bitmap_index(block);
+        if self.bat[bitmap_index] & BAT_STATE_MASK != SB_BLOCK_PRESENT {
+            let bitmap_offset = self.allocate(MIB)?;
+            self.set_bat_entry(bitmap_index, bat_entry(SB_BLOCK_PRESENT, bitmap_offset))?;
+        }
+        let bitmap_offset = bat_entry_offset(self.bat[bitmap_index]);
+
+        let first = self.layout.bitmap_bit(offset);
+        let last = first + length / self.layout.logical_sector_size;
+        let mut bits = vec![0u8; (last.div_ceil(8) - first / 8) as usize];
+        read_at(&mut self.file, bitmap_offset + first / 8, &mut bits)?;
+        for bit in first..last {
+            bits[(bit / 8 - first / 8) as usize] |= 1 << (bit % 8);
+        }
+        write_at(&mut self.file, bitmap_offset + first / 8, &bits)
+    }
+
+    /// Writes data at the given virtual disk offset, which must be aligned
+    /// to the logical sector size like the data length.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let sector_size = self.layout.logical_sector_size;
+        if !offset.is_multiple_of(sector_size) || !(data.len() as u64).is_multiple_of(sector_size) {
+            return Err(invalid_input(format!(
+                "Writes must be aligned to {} bytes",
+                sector_size
+            )));
+        }
+        if offset + data.len() as u64 > self.layout.virtual_size {
+            return Err(invalid_input(format!(
+                "Range {}:{} exceeds the disk size: {}",
+                offset,
+                data.len(),
+                self.layout.virtual_size
+            )));
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let disk_offset = offset + pos as u64;
+            let block = disk_offset / self.layout.block_size;
+            let block_offset = disk_offset % self.layout.block_size;
+            let length = std::cmp::min(
+                self.layout.block_size - block_offset,
+                (data.len() - pos) as u64,
+            );
+            let chunk = &data[pos..pos + length as usize];
+
+            let index = self.layout.payload_index(block);
+            let state = self.bat[index] & BAT_STATE_MASK;
+            let allocated =
+                state == PAYLOAD_BLOCK_FULLY_PRESENT || state == PAYLOAD_BLOCK_PARTIALLY_PRESENT;
+            let file_offset = if allocated {
+                bat_entry_offset(self.bat[index])
+            } else {
+                self.allocate(self.layout.block_size)?
+            };
+
+            write_at(&mut self.file, file_offset + block_offset, chunk)?;
+            if !allocated || state == PAYLOAD_BLOCK_PARTIALLY_PRESENT {
+                // The data must be on disk before the metadata referring to it
+                self.file.sync_data()?;
+            }
+            if self.layout.has_parent && state != PAYLOAD_BLOCK_FULLY_PRESENT {
+                self.set_sectors_present(block, disk_offset, length)?;
+            }
+            if !allocated {
+                let state = if self.layout.has_parent {
+                    PAYLOAD_BLOCK_PARTIALLY_PRESENT
+                } else {
+                    PAYLOAD_BLOCK_FULLY_PRESENT
+                };
+                self.set_bat_entry(index, bat_entry(state, file_offset))?;
+            }
+            pos += length as usize;
+        }
+        Ok(())
+    }
+
+    /// Flushes all the data and metadata to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+fn parent_locator(path: &Path, parent_path: &Path, parent_guid: Guid) -> io::Result<ParentLocator> {
+    let absolute_path = parent_path.canonicalize()?;
+    let mut entries = vec![
+        ("parent_linkage".to_string(), parent_guid.to_string()),
+        (
+            "absolute_win32_path".to_string(),
+            absolute_path.to_string_lossy().to_string(),
+        ),
+    ];
+    // Parents in the same directory can be found even if both disks are moved
+    let directory = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .canonicalize()?;
+    if absolute_path.parent() == Some(directory.as_path()) {
+        if let Some(name) = absolute_path.file_name() {
+            entries.push((
+                "relative_path".to_string(),
+                format!(".\\{}", name.to_string_lossy()),
+            ));
+        }
+    }
+    Ok(ParentLocator { entries })
+}
+
+/// Reads the virtual disk content of a VHDX file, including its parents
+/// when opened with open_chain. Blocks not present read as zeros.
+pub struct VhdxReader {
+    file: File,
+    path: PathBuf,
+    layout: Layout,
+    leave_blocks_allocated: bool,
+    bat: Vec<u64>,
+    data_write_guid: Guid,
+    parent_locator: Option<ParentLocator>,
+    parent: Option<Box<VhdxReader>>,
+    position: u64,
+}
+
+impl VhdxReader {
+    /// Opens a single VHDX file, without its parents.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<VhdxReader> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+
+        let mut signature = [0u8; 8];
+        read_at(&mut file, 0, &mut signature)?;
+        if &signature[..] != FILE_SIGNATURE {
+            return Err(invalid_data("Not a VHDX file"));
+        }
+
+        let mut headers = Vec::new();
+        for offset in HEADER_OFFSETS.iter() {
+            let mut buf = vec![0u8; HEADER_SIZE];
+            read_at(&mut file, *offset, &mut buf)?;
+            headers.extend(Header::parse(&buf));
+        }
+        let header = headers
+            .into_iter()
+            .max_by_key(|h| h.sequence_number)
+            .ok_or_else(|| invalid_data("No valid VHDX header found"))?;
+        if !header.log_guid.is_zero() {
+            return Err(invalid_data("The VHDX log needs to be replayed"));
+        }
+
+        let mut regions = Err(invalid_data("Invalid VHDX region table"));
+        for offset in REGION_TABLE_OFFSETS.iter() {
+            let mut buf = vec![0u8; REGION_TABLE_SIZE];
+            read_at(&mut file, *offset, &mut buf)?;
+            regions = parse_region_table(&buf);
+            if regions.is_ok() {
+                break;
+            }
+        }
+        let ((bat_offset, bat_length), (metadata_offset, metadata_length)) = regions?;
+
+        let mut buf = vec![0u8; metadata_length as usize];
+        read_at(&mut file, metadata_offset, &mut buf)?;
+        let metadata = parse_metadata(&buf)?;
+
+        let bat_entries = metadata.layout.bat_entries();
+        if bat_entries * 8 > bat_length {
+            return Err(invalid_data("VHDX BAT region too small"));
+        }
+        let mut buf = vec![0u8; bat_entries as usize * 8];
+        read_at(&mut file, bat_offset, &mut buf)?;
+        let bat = buf.chunks(8).map(|e| get_u64(e, 0)).collect();
+
+        Ok(VhdxReader {
+            file,
+            path: path.to_path_buf(),
+            layout: metadata.layout,
+            leave_blocks_allocated: metadata.leave_blocks_allocated,
+            bat,
+            data_write_guid: header.data_write_guid,
+            parent_locator: metadata.parent_locator,
+            parent: None,
+            position: 0,
+        })
+    }
+
+    /// Opens a VHDX file and the chain of its parents.
+    pub fn open_chain<P: AsRef<Path>>(path: P) -> io::Result<VhdxReader> {
+        let mut reader = VhdxReader::open(path)?;
+        if reader.layout.has_parent {
+            let (parent_path, linkage) = reader.resolve_parent()?;
+            let parent = VhdxReader::open_chain(parent_path)?;
+            if Some(parent.data_write_guid) != linkage {
+                return Err(invalid_data("The VHDX parent was modified"));
+            }
+            reader.parent = Some(Box::new(parent));
+        }
+        Ok(reader)
+    }
+
+    fn resolve_parent(&self) -> io::Result<(PathBuf, Option<Guid>)> {
+        let locator = self
+            .parent_locator
+            .as_ref()
+            .ok_or_else(|| invalid_data("Missing VHDX parent locator"))?;
+        let linkage = locator.get("parent_linkage").and_then(Guid::parse);
+        let directory = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let relative_path = locator.get("relative_path").map(|p| {
+            directory.join(
+                p.trim_start_matches(".\\")
+                    .replace('\\', std::path::MAIN_SEPARATOR_STR),
+            )
+        });
+        let candidates = relative_path.into_iter().chain(
+            ["absolute_win32_path", "volume_path"]
+                .iter()
+                .filter_map(|k| locator.get(k).map(PathBuf::from)),
+        );
+        for candidate in candidates {
+            if candidate.exists() {
+                return Ok((candidate, linkage));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "VHDX parent not found",
+        ))
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.layout.virtual_size
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.layout.block_size
+    }
+
+    pub fn logical_sector_size(&self) -> u64 {
+        self.layout.logical_sector_size
+    }
+
+    pub fn physical_sector_size(&self) -> u64 {
+        self.layout.physical_sector_size
+    }
+
+    pub fn has_parent(&self) -> bool {
+        self.layout.has_parent
+    }
+
+    /// Fixed disks keep all their payload blocks allocated.
+    pub fn is_fixed(&self) -> bool {
+        self.leave_blocks_allocated
+    }
+
+    /// Returns the first existing path of the parent locator.
+    pub fn parent_path(&self) -> io::Result<PathBuf> {
+        self.resolve_parent().map(|(path, _)| path)
+    }
+
+    pub fn parent_locator(&self) -> Option<&ParentLocator> {
+        self.parent_locator.as_ref()
+    }
+
+    // Returns whether the sectors starting at offset are present in this
+    // layer and for how many bytes, up to length
+    fn sectors_present(&mut self, block: u64, offset: u64, length: u64) -> io::Result<(bool, u64)> {
+        let entry = self.bat[self.layout.bitmap_index(block)];
+        if entry & BAT_STATE_MASK != SB_BLOCK_PRESENT {
+            return Ok((false, length));
+        }
+        let sector_size = self.layout.logical_sector_size;
+        let first = self.layout.bitmap_bit(offset);
+        let last = self.layout.bitmap_bit(offset + length - 1);
+        let mut bits = vec![0u8; (last / 8 - first / 8 + 1) as usize];
+        read_at(
+            &mut self.file,
+            bat_entry_offset(entry) + first / 8,
+            &mut bits,
+        )?;
+        let is_set = |bit: u64| bits[(bit / 8 - first / 8) as usize] & (1 << (bit % 8)) != 0;
+
+        let present = is_set(first);
+        let mut bit = first + 1;
+        while bit <= last && is_set(bit) == present {
+            bit += 1;
+        }
+        let run_end = (offset / sector_size + bit - first) * sector_size;
+        Ok((present, std::cmp::min(run_end - offset, length)))
+    }
+
+    /// Reads from the given virtual disk offset, returning the bytes read.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.layout.virtual_size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block = offset / self.layout.block_size;
+        let block_offset = offset % self.layout.block_size;
+        let length = std::cmp::min(
+            std::cmp::min(self.layout.block_size - block_offset, buf.len() as u64),
+            self.layout.virtual_size - offset,
+        );
+
+        let entry = self.bat[self.layout.payload_index(block)];
+        let (present, length) = match entry & BAT_STATE_MASK {
+            PAYLOAD_BLOCK_FULLY_PRESENT => (true, length),
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => self.sectors_present(block, offset, length)?,
+            _ => (false, length),
+        };
+
+        let buf = &mut buf[..length as usize];
+        if present {
+            read_at(&mut self.file, bat_entry_offset(entry) + block_offset, buf)?;
+        } else {
+            match self.parent {
+                Some(ref mut parent) if entry & BAT_STATE_MASK != PAYLOAD_BLOCK_ZERO => {
+                    let mut pos = 0;
+                    while pos < buf.len() {
+                        match parent.read_at(offset + pos as u64, &mut buf[pos..])? {
+                            0 => return Err(invalid_data("Unexpected end of the VHDX parent")),
+                            read => pos += read,
+                        }
+                    }
+                }
+                _ => {
+                    for b in buf.iter_mut() {
+                        *b = 0;
+                    }
+                }
+            }
+        }
+        Ok(length as usize)
+    }
+}
+
+impl Read for VhdxReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.read_at(self.position, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for VhdxReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.layout.virtual_size as i64)
+                .checked_add(offset)
+                .map(|p| p as u64),
+            SeekFrom::Current(offset) => {
+                (self.position as i64).checked_add(offset).map(|p| p as u64)
+            }
+        };
+        match position {
+            Some(position) if (position as i64) >= 0 => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(invalid_input(
+                "Invalid seek to a negative position".to_string(),
+            )),
+        }
+    }
+}
+
+/// Block size of the streamed VHDX images.
+pub const VHDX_STREAM_BLOCK_SIZE: u64 = 2 * MIB;
+
+/// Streams a dynamic VHDX image of a disk, given the blocks holding data
+/// and their content, without seeking. The layout is the one of the files
+/// created by VhdxWriter, with the payload blocks following the BAT.
+pub struct VhdxStream<R: Read> {
+    map: BlockMap,
+    data: R,
+    layout: Layout,
+    // Everything up to the BAT
+    prefix: Option<Vec<u8>>,
+    bat_chunks: u64,
+    bat_chunk: u64,
+    // Offset in the image of the next payload block in the BAT
+    payload_offset: u64,
+    // Next payload block of the disk to be streamed
+    block: u64,
+    blocks_left: u64,
+    buf: Vec<u8>,
+    buf_pos: usize,
+}
+
+impl<R: Read> VhdxStream<R> {
+    /// data must provide the content of the map's ranges, concatenated.
+    pub fn new(map: BlockMap, data: R) -> io::Result<VhdxStream<R>> {
+        assert_eq!(map.block_size(), VHDX_STREAM_BLOCK_SIZE);
+        let sector_size = DEFAULT_LOGICAL_SECTOR_SIZE as u64;
+        if !map.disk_size().is_multiple_of(sector_size) || map.disk_size() > MAX_VIRTUAL_SIZE {
+            return Err(invalid_input(format!(
+                "Invalid virtual size: {}",
+                map.disk_size()
+            )));
+        }
+
+        let layout = Layout::new(
+            map.disk_size(),
+            VHDX_STREAM_BLOCK_SIZE,
+            sector_size,
+            DEFAULT_PHYSICAL_SECTOR_SIZE as u64,
+            false,
+        );
+        let bat_length = layout.bat_length();
+
+        let mut prefix = vec![0u8; BAT_OFFSET as usize];
+        put(&mut prefix, 0, &file_identifier());
+        let mut header = Header {
+            sequence_number: 0,
+            file_write_guid: Guid::random()?,
+            data_write_guid: Guid::random()?,
+            log_guid: Guid::default(),
+            log_length: LOG_LENGTH as u32,
+            log_offset: LOG_OFFSET,
+        };
+        for offset in HEADER_OFFSETS.iter() {
+            header.sequence_number += 1;
+            put(&mut prefix, *offset as usize, &header.to_bytes());
+        }
+        for offset in REGION_TABLE_OFFSETS.iter() {
+            put(&mut prefix, *offset as usize, &region_table(bat_length));
+        }
+        put(
+            &mut prefix,
+            METADATA_OFFSET as usize,
+            &metadata_region(&layout, false, Guid::random()?, None),
+        );
+
+        Ok(VhdxStream {
+            blocks_left: map.count(),
+            map,
+            data,
+            layout,
+            prefix: Some(prefix),
+            bat_chunks: bat_length / MIB,
+            bat_chunk: 0,
+            payload_offset: BAT_OFFSET + bat_length,
+            block: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+        })
+    }
+
+    /// Size of the whole image.
+    pub fn content_length(&self) -> u64 {
+        BAT_OFFSET + self.layout.bat_length() + self.map.count() * VHDX_STREAM_BLOCK_SIZE
+    }
+
+    // Payload blocks are allocated in order, in the same way as the data
+    // blocks are streamed afterwards
+    fn bat_chunk(&mut self) -> Vec<u8> {
+        let entries = MIB / 8;
+        let first = self.bat_chunk * entries;
+        let mut buf = vec![0u8; MIB as usize];
+        let mut block = first - first / (self.layout.chunk_ratio + 1);
+        let mut payload_offset = self.payload_offset;
+        for index in first..std::cmp::min(first + entries, self.layout.bat_entries()) {
+            // Sector bitmap entries are unused without a parent
+            if (index + 1) % (self.layout.chunk_ratio + 1) == 0 {
+                continue;
+            }
+            if self.map.is_set(block) {
+                let entry = bat_entry(PAYLOAD_BLOCK_FULLY_PRESENT, payload_offset);
+                put(
+                    &mut buf,
+                    ((index - first) * 8) as usize,
+                    &entry.to_le_bytes(),
+                );
+                payload_offset += VHDX_STREAM_BLOCK_SIZE;
+            }
+            block += 1;
+        }
+        self.payload_offset = payload_offset;
+        self.bat_chunk += 1;
+        buf
+    }
+
+    fn payload_block(&mut self) -> io::Result<Vec<u8>> {
+        while !self.map.is_set(self.block) {
+            self.block += 1;
+        }
+        let offset = self.block * VHDX_STREAM_BLOCK_SIZE;
+        let length = std::cmp::min(VHDX_STREAM_BLOCK_SIZE, self.map.disk_size() - offset);
+        // The last block of the disk can be partial
+        let mut buf = vec![0u8; VHDX_STREAM_BLOCK_SIZE as usize];
+        self.data.read_exact(&mut buf[..length as usize])?;
+        self.block += 1;
+        self.blocks_left -= 1;
+        Ok(buf)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(prefix) = self.prefix.take() {
+            Ok(Some(prefix))
+        } else if self.bat_chunk < self.bat_chunks {
+            Ok(Some(self.bat_chunk()))
+        } else if self.blocks_left > 0 {
+            self.payload_block().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<R: Read> Read for VhdxStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf.len() {
+            match self.next_chunk()? {
+                Some(chunk) => {
+                    self.buf = chunk;
+                    self.buf_pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let length = std::cmp::min(buf.len(), self.buf.len() - self.buf_pos);
+        buf[..length].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + length]);
+        self.buf_pos += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    // Directory removed with its content when dropped
+    struct TestDir {
+        path: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let path =
+                std::env::temp_dir().join(format!("rctlib-vhdx-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDir { path }
+        }
+
+        fn join(&self, name: &str) -> PathBuf {
+            self.path.join(name)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn options(disk_type: VirtDiskType, virtual_size: u64) -> VirtDiskCreateOptions {
+        VirtDiskCreateOptions {
+            format: VirtDiskFormat::Vhdx,
+            disk_type,
+            virtual_size,
+            block_size: MIB as u32,
+            logical_sector_size: 0,
+            physical_sector_size: 0,
+            parent_path: None,
+        }
+    }
+
+    fn pattern(seed: u8, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| (i % 251) as u8 ^ seed.wrapping_mul(31))
+            .collect()
+    }
+
+    fn read_all(reader: &mut VhdxReader) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn full_blocks_round_trip() {
+        let dir = TestDir::new("full");
+        let path = dir.join("disk.vhdx");
+        let virtual_size = 4 * MIB;
+        let mut writer =
+            VhdxWriter::create(&path, &options(VirtDiskType::Dynamic, virtual_size)).unwrap();
+        let block = pattern(1, MIB as usize);
+        writer.write_at(MIB, &block).unwrap();
+        // Spanning two blocks
+        let span = pattern(2, MIB as usize);
+        writer.write_at(2 * MIB + MIB / 2, &span).unwrap();
+        writer.flush().unwrap();
+
+        let mut expected = vec![0u8; virtual_size as usize];
+        expected[MIB as usize..2 * MIB as usize].copy_from_slice(&block);
+        expected[(2 * MIB + MIB / 2) as usize..(3 * MIB + MIB / 2) as usize].copy_from_slice(&span);
+
+        let mut reader = VhdxReader::open(&path).unwrap();
+        assert_eq!(reader.virtual_size(), virtual_size);
+        assert_eq!(reader.block_size(), MIB);
+        assert!(!reader.has_parent());
+        assert!(!reader.is_fixed());
+        assert!(read_all(&mut reader) == expected);
+    }
+
+    #[test]
+    fn fixed_disk_round_trip() {
+        let dir = TestDir::new("fixed");
+        let path = dir.join("disk.vhdx");
+        let virtual_size = 2 * MIB;
+        let mut writer =
+            VhdxWriter::create(&path, &options(VirtDiskType::Fixed, virtual_size)).unwrap();
+        let data = pattern(3, 4096);
+        writer.write_at(MIB - 2048, &data).unwrap();
+        writer.flush().unwrap();
+
+        let mut expected = vec![0u8; virtual_size as usize];
+        expected[(MIB - 2048) as usize..(MIB + 2048) as usize].copy_from_slice(&data);
+        let mut reader = VhdxReader::open(&path).unwrap();
+        assert!(reader.is_fixed());
+        assert!(read_all(&mut reader) == expected);
+    }
+
+    #[test]
+    fn tail_block_round_trip() {
+        let dir = TestDir::new("tail");
+        let path = dir.join("disk.vhdx");
+        // The last block holds only 64 KiB
+        let virtual_size = 2 * MIB + 64 * 1024;
+        let mut writer =
+            VhdxWriter::create(&path, &options(VirtDiskType::Dynamic, virtual_size)).unwrap();
+        let tail = pattern(4, 64 * 1024 + 512);
+        writer.write_at(2 * MIB - 512, &tail).unwrap();
+        assert!(writer.write_at(virtual_size - 512, &[0u8; 1024]).is_err());
+        writer.flush().unwrap();
+
+        let mut reader = VhdxReader::open(&path).unwrap();
+        let data = read_all(&mut reader);
+        assert_eq!(data.len() as u64, virtual_size);
+        assert!(data[(2 * MIB - 512) as usize..] == tail[..]);
+        assert!(data[..(2 * MIB - 512) as usize].iter().all(|b| *b == 0));
+
+        let mut buf = [0u8; 4096];
+        assert_eq!(reader.read_at(virtual_size - 512, &mut buf).unwrap(), 512);
+        assert_eq!(reader.read_at(virtual_size, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn unaligned_writes_are_refused() {
+        let dir = TestDir::new("unaligned");
+        let path = dir.join("disk.vhdx");
+        let mut writer =
+            VhdxWriter::create(&path, &options(VirtDiskType::Dynamic, 2 * MIB)).unwrap();
+        assert!(writer.write_at(1, &[0u8; 512]).is_err());
+        assert!(writer.write_at(0, &[0u8; 100]).is_err());
+    }
+
+    #[test]
+    fn differencing_sector_bitmaps() {
+        let dir = TestDir::new("differencing");
+        let parent_path = dir.join("parent.vhdx");
+        let child_path = dir.join("child.avhdx");
+        let virtual_size = 3 * MIB;
+        let parent_data = pattern(5, virtual_size as usize);
+        let mut parent =
+            VhdxWriter::create(&parent_path, &options(VirtDiskType::Dynamic, virtual_size))
+                .unwrap();
+        parent.write_at(0, &parent_data).unwrap();
+        parent.flush().unwrap();
+
+        let mut child_options = options(VirtDiskType::Differencing, 0);
+        child_options.parent_path = Some(parent_path.to_string_lossy().to_string());
+        let mut child = VhdxWriter::create(&child_path, &child_options).unwrap();
+        // Partially present blocks: a few sectors, a run crossing blocks
+        // and a second write to an already allocated block
+        let writes = [
+            (512, pattern(6, 512)),
+            (4096, pattern(7, 3 * 512)),
+            (2 * MIB - 1024, pattern(8, 2048)),
+            (8192, pattern(9, 512)),
+        ];
+        for (offset, data) in writes.iter() {
+            child.write_at(*offset, data).unwrap();
+        }
+        child.flush().unwrap();
+
+        let mut expected = parent_data.clone();
+        let mut layer = vec![0u8; virtual_size as usize];
+        for (offset, data) in writes.iter() {
+            let range = *offset as usize..*offset as usize + data.len();
+            expected[range.clone()].copy_from_slice(data);
+            layer[range].copy_from_slice(data);
+        }
+
+        let mut chain = VhdxReader::open_chain(&child_path).unwrap();
+        assert!(chain.has_parent());
+        assert_eq!(
+            chain.parent_path().unwrap().canonicalize().unwrap(),
+            parent_path.canonicalize().unwrap()
+        );
+        assert!(read_all(&mut chain) == expected);
+
+        // Without the parent, the sectors not present read as zeros
+        let mut single = VhdxReader::open(&child_path).unwrap();
+        assert!(read_all(&mut single) == layer);
+
+        // Reads stop at the boundaries of the runs of present sectors
+        let mut buf = vec![0u8; 8192];
+        assert_eq!(single.read_at(0, &mut buf).unwrap(), 512);
+        assert_eq!(single.read_at(512, &mut buf).unwrap(), 512);
+        assert_eq!(single.read_at(4096, &mut buf).unwrap(), 3 * 512);
+    }
+
+    #[test]
+    fn headers_alternate() {
+        let dir = TestDir::new("headers");
+        let path = dir.join("disk.vhdx");
+        let writer = VhdxWriter::create(&path, &options(VirtDiskType::Dynamic, MIB)).unwrap();
+        drop(writer);
+
+        let read_headers = |file: &mut File| -> Vec<Option<u64>> {
+            HEADER_OFFSETS
+                .iter()
+                .map(|offset| {
+                    let mut buf = vec![0u8; HEADER_SIZE];
+                    read_at(file, *offset, &mut buf).unwrap();
+                    Header::parse(&buf).map(|h| h.sequence_number)
+                })
+                .collect()
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        // Creating the disk writes sequence 1 to the second header, then 2 to
+        // the first one
+        assert_eq!(read_headers(&mut file), vec![Some(2), Some(1)]);
+
+        let mut buf = vec![0u8; HEADER_SIZE];
+        read_at(&mut file, HEADER_OFFSETS[0], &mut buf).unwrap();
+        let mut header = Header::parse(&buf).unwrap();
+        update_headers(&mut file, &mut header).unwrap();
+        assert_eq!(header.sequence_number, 4);
+        assert_eq!(read_headers(&mut file), vec![Some(4), Some(3)]);
+
+        // A torn write of the newest header falls back to the other one
+        write_at(&mut file, HEADER_OFFSETS[0] + 8, &[0xffu8; 8]).unwrap();
+        assert_eq!(read_headers(&mut file), vec![None, Some(3)]);
+        assert!(VhdxReader::open(&path).is_ok());
+
+        // Both headers invalid
+        write_at(&mut file, HEADER_OFFSETS[1] + 8, &[0xffu8; 8]).unwrap();
+        assert!(VhdxReader::open(&path).is_err());
+    }
+}
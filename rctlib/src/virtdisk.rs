@@ -592,6 +592,137 @@ extern "stdcall" {
         Handle: PHANDLE,
     ) -> DWORD;
 }
+pub const _CREATE_VIRTUAL_DISK_VERSION_CREATE_VIRTUAL_DISK_VERSION_UNSPECIFIED:
+    _CREATE_VIRTUAL_DISK_VERSION = 0;
+pub const _CREATE_VIRTUAL_DISK_VERSION_CREATE_VIRTUAL_DISK_VERSION_1: _CREATE_VIRTUAL_DISK_VERSION =
+    1;
+pub const _CREATE_VIRTUAL_DISK_VERSION_CREATE_VIRTUAL_DISK_VERSION_2: _CREATE_VIRTUAL_DISK_VERSION =
+    2;
+pub type _CREATE_VIRTUAL_DISK_VERSION = i32;
+pub use self::_CREATE_VIRTUAL_DISK_VERSION as CREATE_VIRTUAL_DISK_VERSION;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct _CREATE_VIRTUAL_DISK_PARAMETERS {
+    pub Version: CREATE_VIRTUAL_DISK_VERSION,
+    pub __bindgen_anon_1: _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1 {
+    pub Version1: _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1,
+    pub Version2: _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2,
+    _bindgen_union_align: [u64; 15usize],
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1 {
+    pub UniqueId: GUID,
+    pub MaximumSize: ULONGLONG,
+    pub BlockSizeInBytes: ULONG,
+    pub SectorSizeInBytes: ULONG,
+    pub ParentPath: PCWSTR,
+    pub SourcePath: PCWSTR,
+}
+#[test]
+fn bindgen_test_layout__CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1() {
+    assert_eq!(
+        ::std::mem::size_of::<_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1>(),
+        48usize,
+        concat!(
+            "Size of: ",
+            stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1)
+        )
+    );
+    assert_eq!(
+        ::std::mem::align_of::<_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1>(),
+        8usize,
+        concat!(
+            "Alignment of ",
+            stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_1)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2 {
+    pub UniqueId: GUID,
+    pub MaximumSize: ULONGLONG,
+    pub BlockSizeInBytes: ULONG,
+    pub SectorSizeInBytes: ULONG,
+    pub PhysicalSectorSizeInBytes: ULONG,
+    pub ParentPath: PCWSTR,
+    pub SourcePath: PCWSTR,
+    pub OpenFlags: OPEN_VIRTUAL_DISK_FLAG,
+    pub ParentVirtualStorageType: VIRTUAL_STORAGE_TYPE,
+    pub SourceVirtualStorageType: VIRTUAL_STORAGE_TYPE,
+    pub ResiliencyGuid: GUID,
+}
+#[test]
+fn bindgen_test_layout__CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2() {
+    assert_eq!(
+        ::std::mem::size_of::<_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2>(),
+        120usize,
+        concat!(
+            "Size of: ",
+            stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2)
+        )
+    );
+    assert_eq!(
+        ::std::mem::align_of::<_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2>(),
+        8usize,
+        concat!(
+            "Alignment of ",
+            stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::std::ptr::null::<_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2>()))
+                .ParentPath as *const _ as usize
+        },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS__bindgen_ty_1__bindgen_ty_2),
+            "::",
+            stringify!(ParentPath)
+        )
+    );
+}
+#[test]
+fn bindgen_test_layout__CREATE_VIRTUAL_DISK_PARAMETERS() {
+    assert_eq!(
+        ::std::mem::size_of::<_CREATE_VIRTUAL_DISK_PARAMETERS>(),
+        128usize,
+        concat!("Size of: ", stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<_CREATE_VIRTUAL_DISK_PARAMETERS>(),
+        8usize,
+        concat!("Alignment of ", stringify!(_CREATE_VIRTUAL_DISK_PARAMETERS))
+    );
+}
+pub type CREATE_VIRTUAL_DISK_PARAMETERS = _CREATE_VIRTUAL_DISK_PARAMETERS;
+pub type PCREATE_VIRTUAL_DISK_PARAMETERS = *mut _CREATE_VIRTUAL_DISK_PARAMETERS;
+pub const _CREATE_VIRTUAL_DISK_FLAG_CREATE_VIRTUAL_DISK_FLAG_NONE: _CREATE_VIRTUAL_DISK_FLAG = 0;
+pub const _CREATE_VIRTUAL_DISK_FLAG_CREATE_VIRTUAL_DISK_FLAG_FULL_PHYSICAL_ALLOCATION:
+    _CREATE_VIRTUAL_DISK_FLAG = 1;
+pub type _CREATE_VIRTUAL_DISK_FLAG = i32;
+pub use self::_CREATE_VIRTUAL_DISK_FLAG as CREATE_VIRTUAL_DISK_FLAG;
+#[link(name = "VirtDisk")]
+extern "stdcall" {
+    pub fn CreateVirtualDisk(
+        VirtualStorageType: PVIRTUAL_STORAGE_TYPE,
+        Path: PCWSTR,
+        VirtualDiskAccessMask: VIRTUAL_DISK_ACCESS_MASK,
+        SecurityDescriptor: PSECURITY_DESCRIPTOR,
+        Flags: CREATE_VIRTUAL_DISK_FLAG,
+        ProviderSpecificFlags: ULONG,
+        Parameters: PCREATE_VIRTUAL_DISK_PARAMETERS,
+        Overlapped: LPOVERLAPPED,
+        Handle: PHANDLE,
+    ) -> DWORD;
+}
 pub const _ATTACH_VIRTUAL_DISK_VERSION_ATTACH_VIRTUAL_DISK_VERSION_UNSPECIFIED:
     _ATTACH_VIRTUAL_DISK_VERSION = 0;
 pub const _ATTACH_VIRTUAL_DISK_VERSION_ATTACH_VIRTUAL_DISK_VERSION_1: _ATTACH_VIRTUAL_DISK_VERSION =
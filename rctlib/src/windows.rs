@@ -0,0 +1,450 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use std::borrow::Borrow;
+use std::ffi::{OsStr, OsString};
+use std::os::windows::prelude::*;
+
+use crate::virtdisk::*;
+use crate::{
+    RCTInfo, VirtDiskCreateOptions, VirtDiskFormat, VirtDiskType, VirtualDiskChangeRange,
+    VirtualDiskError, ERROR_INVALID_PARAMETER,
+};
+
+const VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN: DWORD = 0;
+const VIRTUAL_STORAGE_TYPE_DEVICE_VHD: DWORD = 2;
+const VIRTUAL_STORAGE_TYPE_DEVICE_VHDX: DWORD = 3;
+
+const VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT: GUID = GUID {
+    Data1: 0xec984aec,
+    Data2: 0xa0f9,
+    Data3: 0x47e9,
+    Data4: [0x90, 0x1f, 0x71, 0x41, 0x5a, 0x66, 0x34, 0x5b],
+};
+
+const FALSE: BOOL = 0;
+const TRUE: BOOL = 1;
+
+const ERROR_SUCCESS: DWORD = 0;
+const ERROR_INSUFFICIENT_BUFFER: DWORD = 122;
+
+const CHANGES_BATCH_SIZE: usize = 100;
+
+fn string_to_u16_vec(s: &str) -> Vec<u16> {
+    OsStr::new(s)
+        .encode_wide()
+        .chain(std::iter::once(0)) // Add NULL terminator
+        .collect()
+}
+
+unsafe fn u16_ptr_to_string(ptr: *const u16) -> String {
+    let len = (0..).take_while(|&i| *ptr.offset(i) != 0).count();
+    let slice = std::slice::from_raw_parts(ptr, len);
+
+    OsString::from_wide(slice).into_string().unwrap()
+}
+
+fn check_result(res: DWORD) -> Result<(), VirtualDiskError> {
+    match res {
+        ERROR_SUCCESS => Ok(()),
+        _ => Err(VirtualDiskError::new(res)),
+    }
+}
+
+pub struct VirtDisk {
+    vhd_handle: HANDLE,
+}
+
+// Virtual disk handles are not tied to the thread that opened them, this
+// allows disks to be opened and read from a thread pool
+unsafe impl Send for VirtDisk {}
+
+/// Iterates over the ranges changed since an RCT ID within a window of the
+/// disk, in batches as returned by QueryChangesVirtualDisk, so that they
+/// don't need to be held in memory all at once. The disk can be borrowed or
+/// owned by the iterator.
+pub struct ChangesIter<D: Borrow<VirtDisk>> {
+    disk: D,
+    change_tracking_id: Vec<u16>,
+    start: u64,
+    byte_offset: u64,
+    end: u64,
+    buf: Vec<_QUERY_CHANGES_VIRTUAL_DISK_RANGE>,
+    // The first batch is queried upfront, to report invalid IDs early
+    first_batch: Option<Vec<VirtualDiskChangeRange>>,
+}
+
+impl<D: Borrow<VirtDisk>> ChangesIter<D> {
+    pub fn new(
+        disk: D,
+        change_tracking_id: &str,
+        byte_offset: u64,
+        length: u64,
+    ) -> Result<ChangesIter<D>, VirtualDiskError> {
+        let virtual_size = disk.borrow().get_virtual_size()?;
+        if byte_offset
+            .checked_add(length)
+            .map_or(true, |end| end > virtual_size)
+        {
+            return Err(VirtualDiskError::new(ERROR_INVALID_PARAMETER));
+        }
+
+        let mut iter = ChangesIter {
+            disk: disk,
+            change_tracking_id: string_to_u16_vec(change_tracking_id),
+            start: byte_offset,
+            byte_offset: byte_offset,
+            end: byte_offset + length,
+            buf: vec![unsafe { std::mem::zeroed() }; CHANGES_BATCH_SIZE],
+            first_batch: None,
+        };
+        iter.first_batch = iter.next_batch()?;
+        Ok(iter)
+    }
+
+    fn next_batch(&mut self) -> Result<Option<Vec<VirtualDiskChangeRange>>, VirtualDiskError> {
+        if self.byte_offset >= self.end {
+            return Ok(None);
+        }
+
+        let mut range_count: ULONG = self.buf.len() as ULONG;
+        let mut processed_length: ULONG64 = 0;
+        check_result(unsafe {
+            QueryChangesVirtualDisk(
+                self.disk.borrow().vhd_handle,
+                self.change_tracking_id.as_ptr(),
+                self.byte_offset,
+                self.end - self.byte_offset,
+                _QUERY_CHANGES_VIRTUAL_DISK_FLAG_QUERY_CHANGES_VIRTUAL_DISK_FLAG_NONE,
+                self.buf.as_mut_ptr(),
+                &mut range_count,
+                &mut processed_length,
+            )
+        })?;
+
+        // Make sure the iteration ends even if nothing was processed
+        self.byte_offset = if processed_length > 0 {
+            self.byte_offset + processed_length
+        } else {
+            self.end
+        };
+        // Ranges can extend outside of the window, clip them to it
+        Ok(Some(
+            self.buf[..range_count as usize]
+                .iter()
+                .filter_map(|r| {
+                    let start = std::cmp::max(r.ByteOffset, self.start);
+                    let stop = std::cmp::min(r.ByteOffset + r.ByteLength, self.end);
+                    if start < stop {
+                        Some(VirtualDiskChangeRange {
+                            offset: start,
+                            length: stop - start,
+                        })
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl<D: Borrow<VirtDisk>> Iterator for ChangesIter<D> {
+    type Item = Result<Vec<VirtualDiskChangeRange>, VirtualDiskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(batch) = self.first_batch.take() {
+            return Some(Ok(batch));
+        }
+        match self.next_batch() {
+            Ok(batch) => batch.map(Ok),
+            Err(e) => {
+                self.byte_offset = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl VirtDisk {
+    pub fn open(vhd_path: &str, read_only: bool) -> Result<VirtDisk, VirtualDiskError> {
+        let vhd_path_u16 = string_to_u16_vec(vhd_path);
+
+        let mut vst: VIRTUAL_STORAGE_TYPE = unsafe { std::mem::zeroed() };
+        vst.DeviceId = VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN;
+        vst.VendorId = VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT;
+
+        let mut op: _OPEN_VIRTUAL_DISK_PARAMETERS = unsafe { std::mem::zeroed() };
+        op.Version = _OPEN_VIRTUAL_DISK_VERSION_OPEN_VIRTUAL_DISK_VERSION_3;
+        unsafe { op.__bindgen_anon_1.Version3.ReadOnly = if read_only { TRUE } else { FALSE } };
+
+        let mut vhd_handle: HANDLE = unsafe { std::mem::zeroed() };
+
+        check_result(unsafe {
+            OpenVirtualDisk(
+                &mut vst,
+                vhd_path_u16.as_ptr(),
+                _VIRTUAL_DISK_ACCESS_MASK_VIRTUAL_DISK_ACCESS_NONE,
+                _OPEN_VIRTUAL_DISK_FLAG_OPEN_VIRTUAL_DISK_FLAG_NONE,
+                &mut op,
+                &mut vhd_handle,
+            )
+        })?;
+        Ok(VirtDisk {
+            vhd_handle: vhd_handle,
+        })
+    }
+
+    pub fn create(
+        vhd_path: &str,
+        options: &VirtDiskCreateOptions,
+    ) -> Result<VirtDisk, VirtualDiskError> {
+        let is_differencing = options.disk_type == VirtDiskType::Differencing;
+        if is_differencing != options.parent_path.is_some()
+            || (!is_differencing && options.virtual_size == 0)
+        {
+            return Err(VirtualDiskError::new(ERROR_INVALID_PARAMETER));
+        }
+
+        let vhd_path_u16 = string_to_u16_vec(vhd_path);
+        let parent_path_u16 = options.parent_path.as_ref().map(|p| string_to_u16_vec(p));
+
+        let mut vst: VIRTUAL_STORAGE_TYPE = unsafe { std::mem::zeroed() };
+        vst.DeviceId = match options.format {
+            VirtDiskFormat::Vhd => VIRTUAL_STORAGE_TYPE_DEVICE_VHD,
+            VirtDiskFormat::Vhdx => VIRTUAL_STORAGE_TYPE_DEVICE_VHDX,
+            VirtDiskFormat::Raw => return Err(VirtualDiskError::new(ERROR_INVALID_PARAMETER)),
+        };
+        vst.VendorId = VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT;
+
+        let mut cp: _CREATE_VIRTUAL_DISK_PARAMETERS = unsafe { std::mem::zeroed() };
+        cp.Version = _CREATE_VIRTUAL_DISK_VERSION_CREATE_VIRTUAL_DISK_VERSION_2;
+        unsafe {
+            let version2 = &mut cp.__bindgen_anon_1.Version2;
+            version2.MaximumSize = options.virtual_size;
+            version2.BlockSizeInBytes = options.block_size;
+            version2.SectorSizeInBytes = options.logical_sector_size;
+            version2.PhysicalSectorSizeInBytes = options.physical_sector_size;
+            version2.ParentPath = parent_path_u16
+                .as_ref()
+                .map_or(std::ptr::null(), |p| p.as_ptr());
+        }
+
+        let flags = match options.disk_type {
+            VirtDiskType::Fixed => {
+                _CREATE_VIRTUAL_DISK_FLAG_CREATE_VIRTUAL_DISK_FLAG_FULL_PHYSICAL_ALLOCATION
+            }
+            _ => _CREATE_VIRTUAL_DISK_FLAG_CREATE_VIRTUAL_DISK_FLAG_NONE,
+        };
+
+        let mut vhd_handle: HANDLE = unsafe { std::mem::zeroed() };
+
+        check_result(unsafe {
+            CreateVirtualDisk(
+                &mut vst,
+                vhd_path_u16.as_ptr(),
+                _VIRTUAL_DISK_ACCESS_MASK_VIRTUAL_DISK_ACCESS_NONE,
+                std::ptr::null_mut(),
+                flags,
+                0,
+                &mut cp,
+                std::ptr::null_mut(),
+                &mut vhd_handle,
+            )
+        })?;
+        Ok(VirtDisk {
+            vhd_handle: vhd_handle,
+        })
+    }
+
+    fn get_info(
+        &self,
+        version: GET_VIRTUAL_DISK_INFO_VERSION,
+    ) -> Result<(Vec<u8>), VirtualDiskError> {
+        let mut buf_size: DWORD = std::mem::size_of::<_GET_VIRTUAL_DISK_INFO>() as DWORD;
+
+        loop {
+            let mut buf: Vec<u8> = vec![0; buf_size as usize];
+
+            let gvdi: &mut _GET_VIRTUAL_DISK_INFO =
+                unsafe { &mut *(buf.as_mut_ptr() as *mut _ as *mut _GET_VIRTUAL_DISK_INFO) };
+            gvdi.Version = version;
+
+            let ret = unsafe {
+                GetVirtualDiskInformation(
+                    self.vhd_handle,
+                    &mut buf_size,
+                    gvdi,
+                    std::ptr::null_mut(),
+                )
+            };
+
+            if ret != ERROR_INSUFFICIENT_BUFFER {
+                check_result(ret)?;
+                return Ok(buf);
+            }
+        }
+    }
+
+    pub fn get_rct_info(&self) -> Result<RCTInfo, VirtualDiskError> {
+        let buf = self
+            .get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_CHANGE_TRACKING_STATE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let rct_enabled = unsafe { gvdi.__bindgen_anon_1.ChangeTrackingState.Enabled } != FALSE;
+        let newer_changes =
+            unsafe { gvdi.__bindgen_anon_1.ChangeTrackingState.NewerChanges } != FALSE;
+        let most_recent_id = unsafe {
+            u16_ptr_to_string(
+                gvdi.__bindgen_anon_1
+                    .ChangeTrackingState
+                    .MostRecentId
+                    .as_ptr(),
+            )
+        };
+
+        Ok(RCTInfo {
+            enabled: rct_enabled,
+            newer_changes: newer_changes,
+            most_recent_id: most_recent_id,
+        })
+    }
+
+    pub fn set_rct_info(&mut self, enabled: bool) -> Result<(), VirtualDiskError> {
+        let mut svdi: _SET_VIRTUAL_DISK_INFO = unsafe { std::mem::zeroed() };
+        svdi.Version = _SET_VIRTUAL_DISK_INFO_VERSION_SET_VIRTUAL_DISK_INFO_CHANGE_TRACKING_STATE;
+        svdi.__bindgen_anon_1.ChangeTrackingEnabled = if enabled { TRUE } else { FALSE };
+
+        check_result(unsafe { SetVirtualDiskInformation(self.vhd_handle, &mut svdi) })
+    }
+
+    pub fn get_virtual_size(&self) -> Result<u64, VirtualDiskError> {
+        let buf = self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_SIZE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let virtual_size = unsafe { gvdi.__bindgen_anon_1.Size.VirtualSize };
+        Ok(virtual_size)
+    }
+
+    /// Size of the disk's blocks, 0 for fixed disks.
+    pub fn get_block_size(&self) -> Result<u64, VirtualDiskError> {
+        let buf = self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_SIZE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let block_size = unsafe { gvdi.__bindgen_anon_1.Size.BlockSize };
+        Ok(block_size as u64)
+    }
+
+    /// Size of the logical sectors, the alignment of the reads.
+    pub fn get_sector_size(&self) -> Result<u64, VirtualDiskError> {
+        let buf = self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_SIZE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let sector_size = unsafe { gvdi.__bindgen_anon_1.Size.SectorSize };
+        Ok(sector_size as u64)
+    }
+
+    pub fn get_parent_path(&self) -> Result<String, VirtualDiskError> {
+        let buf =
+            self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_PARENT_LOCATION)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        Ok(unsafe {
+            u16_ptr_to_string(
+                gvdi.__bindgen_anon_1
+                    .ParentLocation
+                    .ParentLocationBuffer
+                    .as_ptr(),
+            )
+        })
+    }
+
+    pub fn get_virtual_storage_type(&self) -> Result<u32, VirtualDiskError> {
+        let buf = self
+            .get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_VIRTUAL_STORAGE_TYPE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let virtual_storage_type = unsafe { gvdi.__bindgen_anon_1.VirtualStorageType.DeviceId };
+        Ok(virtual_storage_type)
+    }
+
+    pub fn get_provider_sub_type(&self) -> Result<u32, VirtualDiskError> {
+        let buf =
+            self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_PROVIDER_SUBTYPE)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        let provider_sub_type = unsafe { gvdi.__bindgen_anon_1.ProviderSubtype };
+        Ok(provider_sub_type)
+    }
+
+    /// Whether the disk is in use, e.g. by a running VM.
+    pub fn is_loaded(&self) -> Result<bool, VirtualDiskError> {
+        let buf = self.get_info(_GET_VIRTUAL_DISK_INFO_VERSION_GET_VIRTUAL_DISK_INFO_IS_LOADED)?;
+        let gvdi: &_GET_VIRTUAL_DISK_INFO =
+            unsafe { &*(buf.as_ptr() as *const _ as *const _GET_VIRTUAL_DISK_INFO) };
+        Ok(unsafe { gvdi.__bindgen_anon_1.IsLoaded } != FALSE)
+    }
+
+    pub fn query_changes(
+        &self,
+        change_tracking_id: &str,
+    ) -> Result<Vec<VirtualDiskChangeRange>, VirtualDiskError> {
+        let virtual_size = self.get_virtual_size()?;
+        let mut ranges: Vec<VirtualDiskChangeRange> = Vec::new();
+        for batch in ChangesIter::new(self, change_tracking_id, 0, virtual_size)? {
+            ranges.extend(batch?);
+        }
+        Ok(ranges)
+    }
+
+    /// The disk must have been opened writable to be attached read-write.
+    pub fn attach(&self, read_only: bool) -> Result<(), VirtualDiskError> {
+        let mut attach_parameters: _ATTACH_VIRTUAL_DISK_PARAMETERS = unsafe { std::mem::zeroed() };
+        attach_parameters.Version = _ATTACH_VIRTUAL_DISK_VERSION_ATTACH_VIRTUAL_DISK_VERSION_1;
+
+        let mut flags = _ATTACH_VIRTUAL_DISK_FLAG_ATTACH_VIRTUAL_DISK_FLAG_NO_DRIVE_LETTER;
+        if read_only {
+            flags |= _ATTACH_VIRTUAL_DISK_FLAG_ATTACH_VIRTUAL_DISK_FLAG_READ_ONLY;
+        }
+
+        check_result(unsafe {
+            AttachVirtualDisk(
+                self.vhd_handle,
+                std::ptr::null_mut(),
+                flags,
+                0,
+                &mut attach_parameters,
+                std::ptr::null_mut(),
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn get_physical_disk_path(&self) -> Result<String, VirtualDiskError> {
+        let mut buf: Vec<u16> = vec![0u16; 1024];
+        let mut buf_size: ULONG = (buf.len() * 2) as ULONG;
+
+        check_result(unsafe {
+            GetVirtualDiskPhysicalPath(self.vhd_handle, &mut buf_size, buf.as_mut_ptr())
+        })?;
+        Ok(unsafe { u16_ptr_to_string(buf.as_ptr()) })
+    }
+}
+
+impl Drop for VirtDisk {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.vhd_handle) };
+        self.vhd_handle = unsafe { std::mem::zeroed() };
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use ring::digest;
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Logical sector size of raw images and the smallest of virtual disks.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Summary of the data written by a RangeWriter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WriteSummary {
+    pub bytes_written: u64,
+    /// SHA-256 of the data, in the order it was written
+    pub sha256: String,
+}
+
+pub fn hex_digest(digest: &digest::Digest) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Fails unless the range is within a disk of disk_size bytes and aligned to
+/// sector_size, so that ranges can be checked before writing any of them.
+pub fn check_write_range(
+    offset: u64,
+    length: u64,
+    disk_size: u64,
+    sector_size: u64,
+) -> io::Result<()> {
+    match offset.checked_add(length) {
+        Some(end) if end <= disk_size => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Range {}:{} exceeds the disk size: {}",
+                    offset, length, disk_size
+                ),
+            ))
+        }
+    }
+    if !offset.is_multiple_of(sector_size) || !length.is_multiple_of(sector_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Range {}:{} is not aligned to {} bytes",
+                offset, length, sector_size
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes disk ranges, buffering contiguous data into large writes.
+pub struct RangeWriter {
+    dest: File,
+    disk_size: u64,
+    sector_size: u64,
+    write_size: usize,
+    // Data not written yet, starting at buf_offset
+    buf: Vec<u8>,
+    buf_offset: u64,
+    position: u64,
+    bytes_written: u64,
+    digest: digest::Context,
+}
+
+impl RangeWriter {
+    /// Ranges must start and end on a boundary of sector_size, the disk's
+    /// logical sector size, write_size must be a multiple of it.
+    pub fn new(dest: File, disk_size: u64, sector_size: u64, write_size: usize) -> RangeWriter {
+        assert!(
+            sector_size > 0 && write_size > 0 && (write_size as u64).is_multiple_of(sector_size)
+        );
+
+        RangeWriter {
+            dest,
+            disk_size,
+            sector_size,
+            write_size,
+            buf: Vec::with_capacity(write_size),
+            buf_offset: 0,
+            position: 0,
+            bytes_written: 0,
+            digest: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    pub fn check_range(&self, offset: u64, length: u64) -> io::Result<()> {
+        check_write_range(offset, length, self.disk_size, self.sector_size)
+    }
+
+    // Writes the buffered data in multiples of write_size, or all of it
+    // when the current extent is complete
+    fn write_buffered(&mut self, extent_done: bool) -> io::Result<()> {
+        let length = if extent_done {
+            self.buf.len()
+        } else {
+            self.buf.len() / self.write_size * self.write_size
+        };
+        if length == 0 {
+            return Ok(());
+        }
+        if extent_done && !(length as u64).is_multiple_of(self.sector_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Range ending at {} is not aligned to {} bytes",
+                    self.buf_offset + length as u64,
+                    self.sector_size
+                ),
+            ));
+        }
+
+        if self.position != self.buf_offset {
+            self.dest.seek(SeekFrom::Start(self.buf_offset))?;
+        }
+        self.dest.write_all(&self.buf[..length])?;
+        self.buf.drain(..length);
+        self.buf_offset += length as u64;
+        self.position = self.buf_offset;
+        Ok(())
+    }
+
+    /// Writes data at the given disk offset. Ranges can be written in any
+    /// order and split across multiple calls.
+    pub fn write_range(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= self.disk_size => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Range {}:{} exceeds the disk size: {}",
+                        offset,
+                        data.len(),
+                        self.disk_size
+                    ),
+                ))
+            }
+        }
+
+        if offset != self.buf_offset + self.buf.len() as u64 {
+            self.write_buffered(true)?;
+            if !offset.is_multiple_of(self.sector_size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Range starting at {} is not aligned to {} bytes",
+                        offset, self.sector_size
+                    ),
+                ));
+            }
+            self.buf_offset = offset;
+        }
+
+        self.buf.extend_from_slice(data);
+        self.digest.update(data);
+        self.bytes_written += data.len() as u64;
+        self.write_buffered(false)
+    }
+
+    /// Writes any buffered data and flushes it to the disk.
+    pub fn finish(mut self) -> io::Result<WriteSummary> {
+        self.write_buffered(true)?;
+        self.dest.sync_all()?;
+        Ok(WriteSummary {
+            bytes_written: self.bytes_written,
+            sha256: hex_digest(&self.digest.finish()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, OpenOptions};
+
+    fn temp_writer(name: &str, sector_size: u64) -> RangeWriter {
+        let path =
+            std::env::temp_dir().join(format!("rctlib-writer-{}-{}.img", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        // The open file remains usable
+        fs::remove_file(&path).unwrap();
+        file.set_len(65536).unwrap();
+        RangeWriter::new(file, 65536, sector_size, 4096)
+    }
+
+    #[test]
+    fn ranges_are_aligned_to_the_sector_size() {
+        let mut writer = temp_writer("aligned", 4096);
+        writer.write_range(4096, &[1u8; 4096]).unwrap();
+        let e = writer.write_range(512, &[1u8; 512]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let mut writer = temp_writer("unaligned-end", 4096);
+        writer.write_range(0, &[1u8; 512]).unwrap();
+        let e = writer.finish().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let mut writer = temp_writer("512", 512);
+        writer.write_range(512, &[1u8; 512]).unwrap();
+        writer.write_range(8192, &[2u8; 1024]).unwrap();
+        assert_eq!(writer.finish().unwrap().bytes_written, 1536);
+    }
+
+    #[test]
+    fn overflowing_ranges_are_refused() {
+        let mut writer = temp_writer("overflow", 512);
+        let e = writer
+            .write_range(u64::MAX - 511, &[1u8; 1024])
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = writer.write_range(65024, &[1u8; 1024]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
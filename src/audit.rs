@@ -0,0 +1,272 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::tokio::io::{AsyncRead, ReadBuf};
+use rocket::{Data, Request, Response};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::ClientKeyId;
+
+/// Request details that are only known to the handlers / responders.
+pub struct AuditDetails {
+    pub ranges: Option<usize>,
+}
+
+struct RequestStart(Instant);
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    timestamp: String,
+    key_id: Option<String>,
+    remote_addr: Option<String>,
+    method: String,
+    route: String,
+    disk_path: Option<String>,
+    rct_id: Option<String>,
+    // Requested RCT state, when changing it
+    rct_enabled: Option<bool>,
+    ranges: Option<usize>,
+    bytes_sent: u64,
+    duration_ms: u64,
+    status: u16,
+    outcome: String,
+}
+
+/// JSON lines audit log, rotated when it exceeds max_size bytes.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, max_size: u64, max_files: u32) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+// Value of the matched route's <name> path parameter
+fn route_param(request: &Request<'_>, name: &str) -> Option<String> {
+    let param = format!("<{}>", name);
+    let index = request
+        .route()?
+        .uri
+        .unmounted_origin
+        .path()
+        .segments()
+        .position(|s| s == param)?;
+    request.routed_segment(index).map(|s| s.to_string())
+}
+
+// Value of the matched route's boolean <name> query parameter
+fn route_query_flag(request: &Request<'_>, name: &str) -> Option<bool> {
+    let param = format!("<{}>", name);
+    let query = request.route()?.uri.query()?;
+    if !query.split('&').any(|q| q == param) {
+        return None;
+    }
+    request.query_value::<bool>(name)?.ok()
+}
+
+fn write_record(log: &Mutex<AuditLog>, record: &AuditRecord) {
+    // Never fail a request because of the audit log, but make sure it is noticed
+    if let Err(e) = log.lock().unwrap().write(record) {
+        error!("Failed to write audit record: {}", e);
+    }
+}
+
+/// Wraps a response body, writing the audit record once the body is dropped,
+/// so that the bytes actually sent and the full transfer duration are logged.
+struct AuditedBody<R: AsyncRead + Unpin> {
+    inner: R,
+    log: Arc<Mutex<AuditLog>>,
+    record: AuditRecord,
+    start: Instant,
+    complete: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AuditedBody<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = buf.filled().len() - filled;
+                if read == 0 && buf.remaining() > 0 {
+                    self.complete = true;
+                }
+                self.record.bytes_sent += read as u64;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                self.record.outcome = format!("error: {}", e);
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Drop for AuditedBody<R> {
+    fn drop(&mut self) {
+        if !self.complete && self.record.outcome == "success" {
+            self.record.outcome = "aborted".to_string();
+        }
+        self.record.duration_ms = self.start.elapsed().as_millis() as u64;
+        write_record(&self.log, &self.record);
+    }
+}
+
+pub struct AuditFairing {
+    log: Arc<Mutex<AuditLog>>,
+}
+
+impl AuditFairing {
+    pub fn new(log: AuditLog) -> AuditFairing {
+        AuditFairing {
+            log: Arc::new(Mutex::new(log)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AuditFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let key_id = request.local_cache(|| ClientKeyId(None)).0.clone();
+        let ranges = request.local_cache(|| AuditDetails { ranges: None }).ranges;
+
+        let disk_path = route_param(request, "path");
+        let rct_id = route_param(request, "rct_id");
+        let rct_enabled = route_query_flag(request, "enabled");
+
+        let status = response.status();
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            key_id,
+            remote_addr: request.remote().map(|a| a.to_string()),
+            method: request.method().to_string(),
+            route: request
+                .route()
+                .map_or_else(|| request.uri().path().to_string(), |r| r.uri.to_string()),
+            disk_path,
+            rct_id,
+            rct_enabled,
+            ranges,
+            bytes_sent: 0,
+            duration_ms: 0,
+            status: status.code,
+            outcome: if status.code < 400 {
+                "success".to_string()
+            } else {
+                "failure".to_string()
+            },
+        };
+
+        // Sized bodies are sent as is, only streamed ones are worth tracking
+        let body = response.body_mut();
+        if body.is_some() && body.preset_size().is_none() && request.method() != Method::Head {
+            let body = body.take();
+            response.set_streamed_body(AuditedBody {
+                inner: body,
+                log: self.log.clone(),
+                record,
+                start,
+                complete: false,
+            });
+        } else {
+            let mut record = record;
+            record.bytes_sent = match request.method() {
+                Method::Head => 0,
+                _ => body.preset_size().unwrap_or(0) as u64,
+            };
+            record.duration_ms = start.elapsed().as_millis() as u64;
+            write_record(&self.log, &record);
+        }
+    }
+}
@@ -0,0 +1,807 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::status;
+use rocket::State;
+
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use rctlib::*;
+
+use crate::formats::{read_structured, Structured, StructuredFormat};
+use crate::history::RctHistory;
+use crate::limits::StreamPermit;
+use crate::manifest::ManifestStore;
+use crate::metrics::Metrics;
+use crate::restore::{FRAMES_END_OFFSET, FRAME_HEADER_SIZE};
+use crate::{
+    blocking, check_loaded, disk_changed, open_vdisk, AuthKeyGuard, DiskContentResponder,
+    ReadConfig, VirtDiskReader,
+};
+
+// Maps the errors of a changes query for the given RCT ID
+pub fn changes_error(rct_id: &str) -> impl Fn(VirtualDiskError) -> status::Custom<String> + '_ {
+    move |e| match e.result() {
+        ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION => {
+            status::Custom(Status::NotFound, format!("RCT ID not found: {}", rct_id))
+        }
+        _ => panic!("{}", e),
+    }
+}
+
+/// Starts querying the changes since rct_id within the window starting at
+/// byte_offset, by default until the end of the disk.
+pub fn changes_iter<D: Borrow<VirtDisk>>(
+    vdisk: D,
+    rct_id: &str,
+    byte_offset: Option<u64>,
+    length: Option<u64>,
+) -> Result<ChangesIter<D>, status::Custom<String>> {
+    let disk_size = vdisk.borrow().get_virtual_size().unwrap();
+    let byte_offset = byte_offset.unwrap_or(0);
+    let length = length.unwrap_or_else(|| disk_size.saturating_sub(byte_offset));
+    ChangesIter::new(vdisk, rct_id, byte_offset, length).map_err(|e| match e.result() {
+        ERROR_INVALID_PARAMETER => status::Custom(
+            Status::BadRequest,
+            format!(
+                "Window {}:{} exceeds the disk size: {}",
+                byte_offset, length, disk_size
+            ),
+        ),
+        _ => changes_error(rct_id)(e),
+    })
+}
+
+// One JSON object per line for each changed range, queried while sent
+struct ChangesNdjson {
+    iter: ChangesIter<VirtDisk>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    ranges: usize,
+    start: Instant,
+    metrics: Metrics,
+    done: bool,
+}
+
+impl Read for ChangesNdjson {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending_pos == self.pending.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.pending.clear();
+            self.pending_pos = 0;
+            match self.iter.next() {
+                Some(batch) => {
+                    let batch = batch.map_err(io::Error::other)?;
+                    for range in batch.iter() {
+                        serde_json::to_writer(&mut self.pending, range)?;
+                        self.pending.push(b'\n');
+                    }
+                    self.ranges += batch.len();
+                }
+                None => {
+                    self.metrics
+                        .query_changes_done(self.start.elapsed(), Some(self.ranges));
+                    self.done = true;
+                }
+            }
+        }
+
+        let length = std::cmp::min(buf.len(), self.pending.len() - self.pending_pos);
+        buf[..length].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + length]);
+        self.pending_pos += length;
+        Ok(length)
+    }
+}
+
+/// Streams the changed ranges as newline delimited JSON, without holding
+/// them all in memory. Ranked after the JSON array variant, which is
+/// returned when no format is requested.
+#[get(
+    "/vdisk/<path>/rct/<rct_id>/changes?<byte_offset>&<length>",
+    format = "application/x-ndjson",
+    rank = 2
+)]
+pub async fn query_disk_changes_ndjson(
+    path: String,
+    rct_id: String,
+    byte_offset: Option<u64>,
+    length: Option<u64>,
+    metrics: &State<Metrics>,
+    _key: AuthKeyGuard,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let metrics = metrics.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let start = Instant::now();
+        let iter = match changes_iter(vdisk, &rct_id, byte_offset, length) {
+            Ok(iter) => iter,
+            Err(e) => {
+                metrics.query_changes_done(start.elapsed(), None);
+                return Err(e);
+            }
+        };
+        Ok(DiskContentResponder {
+            reader: Box::new(ChangesNdjson {
+                iter,
+                pending: Vec::new(),
+                pending_pos: 0,
+                ranges: 0,
+                start,
+                metrics,
+                done: false,
+            }),
+            content_length: None,
+            content_type: "application/x-ndjson",
+            headers: Vec::new(),
+            ranges: None,
+        })
+    })
+    .await
+}
+
+// Bitmaps larger than 8 MiB are refused, a coarser granularity is needed
+const MAX_BITMAP_BLOCKS: u64 = 64 * 1024 * 1024;
+
+/// Returns the changes as a block bitmap, serialized as described in
+/// BLOCK_MAP_MAGIC, with a bit per block of granularity bytes, by default the
+/// disk's block size. The bitmap is gzip compressed if requested.
+#[get(
+    "/vdisk/<path>/rct/<rct_id>/changes?<granularity>&<compress>",
+    format = "application/octet-stream",
+    rank = 3
+)]
+pub async fn query_disk_changes_bitmap(
+    path: String,
+    rct_id: String,
+    granularity: Option<u64>,
+    compress: Option<bool>,
+    metrics: &State<Metrics>,
+    _key: AuthKeyGuard,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let metrics = metrics.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let virtual_size = vdisk.get_virtual_size().unwrap();
+        let granularity = granularity.unwrap_or_else(|| match vdisk.get_block_size().unwrap() {
+            0 => VHDX_DEFAULT_BLOCK_SIZE as u64,
+            block_size => block_size,
+        });
+        if granularity == 0
+            || !granularity.is_multiple_of(SECTOR_SIZE)
+            || virtual_size / granularity >= MAX_BITMAP_BLOCKS
+        {
+            return Err(status::Custom(
+                Status::BadRequest,
+                format!(
+                    "The granularity must be a multiple of {} bytes, with at most {} blocks: {}",
+                    SECTOR_SIZE, MAX_BITMAP_BLOCKS, granularity
+                ),
+            ));
+        }
+
+        let mut map = BlockMap::new(granularity, virtual_size);
+        let start = Instant::now();
+        let ranges = changes_iter(&vdisk, &rct_id, None, None).and_then(|iter| {
+            let mut ranges = 0;
+            for batch in iter {
+                let batch = batch.map_err(changes_error(&rct_id))?;
+                map.set_ranges(&batch);
+                ranges += batch.len();
+            }
+            Ok(ranges)
+        });
+        metrics.query_changes_done(start.elapsed(), ranges.as_ref().ok().copied());
+        let ranges = ranges?;
+
+        let mut data = map.to_bytes();
+        let mut headers = vec![("X-RCT-Ranges", ranges.to_string())];
+        if compress.unwrap_or(false) {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data).unwrap();
+            data = encoder.finish().unwrap();
+            headers.push(("Content-Encoding", "gzip".to_string()));
+        }
+        Ok(DiskContentResponder {
+            content_length: Some(data.len() as u64),
+            reader: Box::new(io::Cursor::new(data)),
+            content_type: "application/octet-stream",
+            headers,
+            ranges: Some(ranges),
+        })
+    })
+    .await
+}
+
+const DEFAULT_SUMMARY_REGIONS: u64 = 16;
+const MAX_SUMMARY_REGIONS: u64 = 4096;
+
+/// Statistics of the ranges changed since an RCT ID, with the changed bytes
+/// in each of the disk's equally sized regions.
+#[derive(Debug, Serialize)]
+pub struct ChangesSummary {
+    pub rct_id: String,
+    pub virtual_size: u64,
+    pub ranges: usize,
+    pub bytes: u64,
+    pub min_range_size: u64,
+    pub max_range_size: u64,
+    pub median_range_size: u64,
+    pub changed_percent: f64,
+    pub region_size: u64,
+    pub regions: Vec<u64>,
+}
+
+impl ChangesSummary {
+    fn new(rct_id: String, virtual_size: u64, regions: u64) -> ChangesSummary {
+        let region_size = std::cmp::max(virtual_size.div_ceil(regions), 1);
+        ChangesSummary {
+            rct_id,
+            virtual_size,
+            ranges: 0,
+            bytes: 0,
+            min_range_size: 0,
+            max_range_size: 0,
+            median_range_size: 0,
+            changed_percent: 0.0,
+            region_size,
+            regions: vec![0; virtual_size.div_ceil(region_size) as usize],
+        }
+    }
+
+    fn add_range(&mut self, range: &VirtualDiskChangeRange) {
+        // Ranges can span multiple regions, anything past the end of the
+        // disk is ignored
+        let mut offset = range.offset;
+        let end = std::cmp::min(range.offset.saturating_add(range.length), self.virtual_size);
+        while offset < end {
+            let region = offset / self.region_size;
+            let region_end = std::cmp::min((region + 1) * self.region_size, end);
+            match self.regions.get_mut(region as usize) {
+                Some(changed) => *changed += region_end - offset,
+                None => break,
+            }
+            self.bytes += region_end - offset;
+            offset = region_end;
+        }
+    }
+
+    fn finish(&mut self, mut sizes: Vec<u64>) {
+        sizes.sort_unstable();
+        self.ranges = sizes.len();
+        if let (Some(min), Some(max)) = (sizes.first(), sizes.last()) {
+            self.min_range_size = *min;
+            self.max_range_size = *max;
+            let middle = sizes.len() / 2;
+            self.median_range_size = if sizes.len().is_multiple_of(2) {
+                (sizes[middle - 1] + sizes[middle]) / 2
+            } else {
+                sizes[middle]
+            };
+        }
+        if self.virtual_size > 0 {
+            self.changed_percent = self.bytes as f64 * 100.0 / self.virtual_size as f64;
+        }
+    }
+}
+
+/// Summarizes the changes since rct_id without returning the ranges, e.g. to
+/// estimate the size of an incremental backup before starting it.
+#[get("/vdisk/<path>/rct/<rct_id>/summary?<regions>")]
+pub async fn get_changes_summary(
+    path: String,
+    rct_id: String,
+    regions: Option<u64>,
+    metrics: &State<Metrics>,
+    _format: StructuredFormat,
+    _key: AuthKeyGuard,
+) -> Result<Structured<ChangesSummary>, status::Custom<String>> {
+    let regions = regions.unwrap_or(DEFAULT_SUMMARY_REGIONS);
+    if regions == 0 || regions > MAX_SUMMARY_REGIONS {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!(
+                "The regions must be between 1 and {}: {}",
+                MAX_SUMMARY_REGIONS, regions
+            ),
+        ));
+    }
+
+    let metrics = metrics.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let virtual_size = vdisk.get_virtual_size().unwrap();
+        let mut summary = ChangesSummary::new(rct_id.clone(), virtual_size, regions);
+
+        let start = Instant::now();
+        let sizes = changes_iter(&vdisk, &rct_id, None, None).and_then(|iter| {
+            // Only the sizes are kept, for the median
+            let mut sizes = Vec::new();
+            for batch in iter {
+                for range in batch.map_err(changes_error(&rct_id))? {
+                    summary.add_range(&range);
+                    sizes.push(range.length);
+                }
+            }
+            Ok(sizes)
+        });
+        metrics.query_changes_done(start.elapsed(), sizes.as_ref().ok().map(|s| s.len()));
+        summary.finish(sizes?);
+        Ok(Structured(summary))
+    })
+    .await
+}
+
+/// Sent after the frames' end marker, with the RCT state of the disk before
+/// and after its content was read, telling whether it changed meanwhile.
+#[derive(Debug, Serialize)]
+struct FramesTrailer {
+    ranges: usize,
+    bytes: u64,
+    most_recent_id_start: String,
+    most_recent_id_end: String,
+    newer_changes_start: bool,
+    newer_changes_end: bool,
+    disk_changed: Option<bool>,
+}
+
+fn frame_header(offset: u64, length: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FRAME_HEADER_SIZE);
+    header.extend_from_slice(&offset.to_be_bytes());
+    header.extend_from_slice(&length.to_be_bytes());
+    header
+}
+
+// End marker and trailer of the frames read from reader
+fn frames_trailer(
+    reader: &VirtDiskReader,
+    ranges: usize,
+    bytes: u64,
+    rct_info_start: &RCTInfo,
+) -> io::Result<Vec<u8>> {
+    // Queried on the same handle the content was read from
+    let rct_info = reader
+        .get_virt_disk()
+        .get_rct_info()
+        .map_err(io::Error::other)?;
+    let trailer = FramesTrailer {
+        ranges,
+        bytes,
+        most_recent_id_start: rct_info_start.most_recent_id.clone(),
+        most_recent_id_end: rct_info.most_recent_id.clone(),
+        newer_changes_start: rct_info_start.newer_changes,
+        newer_changes_end: rct_info.newer_changes,
+        disk_changed: disk_changed(rct_info_start, &rct_info),
+    };
+    let mut data = frame_header(FRAMES_END_OFFSET, 0);
+    serde_json::to_writer(&mut data, &trailer)?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+/// Frames with the content of the given ranges, in the format accepted by
+/// restores, followed by the end marker and a JSON trailer.
+pub struct FramedContent {
+    reader: VirtDiskReader,
+    ranges: VecDeque<VirtualDiskChangeRange>,
+    // Data left in the current frame
+    frame_left: u64,
+    // Frame headers and trailer, not sent yet
+    pending: Vec<u8>,
+    pending_pos: usize,
+    ranges_count: usize,
+    bytes: u64,
+    rct_info_start: RCTInfo,
+    done: bool,
+}
+
+impl FramedContent {
+    pub fn new(
+        reader: VirtDiskReader,
+        ranges: Vec<VirtualDiskChangeRange>,
+        rct_info_start: RCTInfo,
+    ) -> FramedContent {
+        FramedContent {
+            reader,
+            ranges_count: ranges.len(),
+            ranges: ranges.into_iter().filter(|r| r.length > 0).collect(),
+            frame_left: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+            bytes: 0,
+            rct_info_start,
+            done: false,
+        }
+    }
+
+    fn trailer(&self) -> io::Result<Vec<u8>> {
+        frames_trailer(
+            &self.reader,
+            self.ranges_count,
+            self.bytes,
+            &self.rct_info_start,
+        )
+    }
+}
+
+impl Read for FramedContent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_pos == self.pending.len() && self.frame_left == 0 {
+            match self.ranges.pop_front() {
+                Some(range) => {
+                    self.pending = frame_header(range.offset, range.length);
+                    self.frame_left = range.length;
+                }
+                None if !self.done => {
+                    self.pending = self.trailer()?;
+                    self.done = true;
+                }
+                None => return Ok(0),
+            }
+            self.pending_pos = 0;
+        }
+
+        if self.pending_pos < self.pending.len() {
+            let length = std::cmp::min(buf.len(), self.pending.len() - self.pending_pos);
+            buf[..length]
+                .copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + length]);
+            self.pending_pos += length;
+            return Ok(length);
+        }
+
+        let length = std::cmp::min(buf.len() as u64, self.frame_left) as usize;
+        let read = self.reader.read(&mut buf[..length])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The disk content ended before the ranges",
+            ));
+        }
+        self.frame_left -= read as u64;
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+// Frames with the blocks whose hash differs from the given ones, reading
+// the whole disk, followed by the end marker and a JSON trailer
+struct DeltaContent {
+    reader: VirtDiskReader,
+    hashes: BlockHashes,
+    disk_size: u64,
+    block: u64,
+    // Frame not sent yet
+    pending: Vec<u8>,
+    pending_pos: usize,
+    frames: usize,
+    bytes: u64,
+    rct_info_start: RCTInfo,
+    done: bool,
+}
+
+impl DeltaContent {
+    fn new(
+        reader: VirtDiskReader,
+        hashes: BlockHashes,
+        disk_size: u64,
+        rct_info_start: RCTInfo,
+    ) -> DeltaContent {
+        DeltaContent {
+            reader,
+            hashes,
+            disk_size,
+            block: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+            frames: 0,
+            bytes: 0,
+            rct_info_start,
+            done: false,
+        }
+    }
+
+    // Reads the next block into a frame, left empty if unchanged
+    fn next_block(&mut self) -> io::Result<()> {
+        let offset = self.block * self.hashes.block_size;
+        let length = std::cmp::min(self.hashes.block_size, self.disk_size - offset);
+        self.pending.clear();
+        self.pending
+            .extend_from_slice(&frame_header(offset, length));
+        self.pending.resize(FRAME_HEADER_SIZE + length as usize, 0);
+        self.reader
+            .read_exact(&mut self.pending[FRAME_HEADER_SIZE..])?;
+        if self
+            .hashes
+            .is_changed(self.block, &self.pending[FRAME_HEADER_SIZE..])
+        {
+            self.frames += 1;
+            self.bytes += length;
+        } else {
+            self.pending.clear();
+        }
+        self.block += 1;
+        Ok(())
+    }
+}
+
+impl Read for DeltaContent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending_pos == self.pending.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.pending_pos = 0;
+            if self.block * self.hashes.block_size < self.disk_size {
+                self.next_block()?;
+            } else {
+                self.pending =
+                    frames_trailer(&self.reader, self.frames, self.bytes, &self.rct_info_start)?;
+                self.done = true;
+            }
+        }
+
+        let length = std::cmp::min(buf.len(), self.pending.len() - self.pending_pos);
+        buf[..length].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + length]);
+        self.pending_pos += length;
+        Ok(length)
+    }
+}
+
+// Each block is buffered while hashed
+const MAX_HASH_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Validates the block size of hashes sent by clients or to compute.
+pub fn check_hash_block_size(block_size: u64) -> Result<(), status::Custom<String>> {
+    if block_size == 0
+        || !block_size.is_multiple_of(SECTOR_SIZE)
+        || block_size > MAX_HASH_BLOCK_SIZE
+    {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!(
+                "The block size must be a multiple of {} up to {}: {}",
+                SECTOR_SIZE, MAX_HASH_BLOCK_SIZE, block_size
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Block hashes of a previous backup, which can be much larger than the
+// other structured bodies
+pub struct HashesBody(BlockHashes);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for HashesBody {
+    type Error = String;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        read_structured(request, data, "hashes", 64.mebibytes())
+            .await
+            .map(HashesBody)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn changes_content(
+    path: String,
+    rct_id: String,
+    hashes: Option<BlockHashes>,
+    read_config: ReadConfig,
+    metrics: Metrics,
+    history: RctHistory,
+    store: ManifestStore,
+    permit: StreamPermit,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+    // Changes made after this point are included in the next query
+    let rct_info = vdisk.get_rct_info().unwrap();
+    let most_recent_id = rct_info.most_recent_id.clone();
+    history.record(&path, &most_recent_id, None);
+
+    let start = Instant::now();
+    let disk_changes = vdisk.query_changes(&rct_id);
+    metrics.query_changes_done(start.elapsed(), disk_changes.as_ref().ok().map(|c| c.len()));
+    let mut hashes = hashes;
+    let disk_changes = match disk_changes {
+        Ok(disk_changes) => Some(disk_changes),
+        Err(e) => {
+            // Falls back to the given hashes, or to the manifest stored for
+            // the RCT ID
+            let missing = e.result() == ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION;
+            if missing && hashes.is_none() {
+                hashes = store.load(&path, &rct_id);
+            }
+            if !missing || hashes.is_none() {
+                return Err(changes_error(&rct_id)(e));
+            }
+            None
+        }
+    };
+
+    let read_error = |e: io::Error| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("Unable to read {}: {}", path, e),
+        )
+    };
+    check_loaded(&vdisk, &path, &read_config)?;
+    if let Err(e) = vdisk.attach(true) {
+        metrics.disk_attach_failed();
+        panic!("{}", e);
+    }
+    metrics.disk_attached();
+
+    let disk_changes = match disk_changes {
+        Some(disk_changes) => disk_changes,
+        None => {
+            let disk_size = vdisk.get_virtual_size().unwrap();
+            let reader = VirtDiskReader::open(
+                Box::new(vdisk),
+                vec![VirtualDiskChangeRange {
+                    offset: 0,
+                    length: disk_size,
+                }],
+                &read_config,
+                metrics,
+                permit,
+            )
+            .map_err(read_error)?;
+            return Ok(DiskContentResponder {
+                reader: Box::new(DeltaContent::new(
+                    reader,
+                    hashes.unwrap(),
+                    disk_size,
+                    rct_info,
+                )),
+                content_length: None,
+                content_type: "application/x-rct-frames",
+                headers: vec![
+                    ("X-RCT-Most-Recent-Id", most_recent_id),
+                    ("X-RCT-Delta", "hashes".to_string()),
+                ],
+                ranges: None,
+            });
+        }
+    };
+
+    let reader = VirtDiskReader::open(
+        Box::new(vdisk),
+        disk_changes.clone(),
+        &read_config,
+        metrics,
+        permit,
+    )
+    .map_err(read_error)?;
+    let ranges = reader.ranges_count;
+    Ok(DiskContentResponder {
+        reader: Box::new(FramedContent::new(reader, disk_changes, rct_info)),
+        content_length: None,
+        content_type: "application/x-rct-frames",
+        headers: vec![
+            ("X-RCT-Most-Recent-Id", most_recent_id),
+            ("X-RCT-Ranges", ranges.to_string()),
+            ("X-RCT-Delta", "rct".to_string()),
+        ],
+        ranges: Some(ranges),
+    })
+}
+
+/// Streams the ranges changed since rct_id as frames, querying the changes
+/// and reading them on the same disk handle, in a single request. The RCT ID
+/// observed before querying the changes is returned in a header, and the one
+/// observed after reading them in the trailer. If rct_id is not valid
+/// anymore, falls back to the manifest stored for it, if any.
+#[get("/vdisk/<path>/rct/<rct_id>/content")]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_changes_content(
+    path: String,
+    rct_id: String,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    history: &State<RctHistory>,
+    store: &State<ManifestStore>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let read_config = *read_config.inner();
+    let metrics = metrics.inner().clone();
+    let history = history.inner().clone();
+    let store = store.inner().clone();
+    blocking(move || {
+        changes_content(
+            path,
+            rct_id,
+            None,
+            read_config,
+            metrics,
+            history,
+            store,
+            permit,
+        )
+    })
+    .await
+}
+
+/// Same as get_changes_content, but when rct_id is not valid anymore, e.g.
+/// after the change tracking was reset, returns the blocks whose hash differs
+/// from the given ones instead, reading the whole disk. The X-RCT-Delta
+/// header tells which one is returned.
+#[post("/vdisk/<path>/rct/<rct_id>/content", data = "<hashes>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_changes_content_post(
+    path: String,
+    rct_id: String,
+    hashes: HashesBody,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    history: &State<RctHistory>,
+    store: &State<ManifestStore>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let hashes = hashes.0;
+    check_hash_block_size(hashes.block_size)?;
+
+    let read_config = *read_config.inner();
+    let metrics = metrics.inner().clone();
+    let history = history.inner().clone();
+    let store = store.inner().clone();
+    blocking(move || {
+        changes_content(
+            path,
+            rct_id,
+            Some(hashes),
+            read_config,
+            metrics,
+            history,
+            store,
+            permit,
+        )
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_ignores_ranges_past_the_disk() {
+        let mut summary = ChangesSummary::new("rct".to_string(), 10000, 4);
+        summary.add_range(&VirtualDiskChangeRange {
+            offset: 2000,
+            length: 1000,
+        });
+        summary.add_range(&VirtualDiskChangeRange {
+            offset: 9000,
+            length: 5000,
+        });
+        summary.add_range(&VirtualDiskChangeRange {
+            offset: u64::MAX - 10,
+            length: 100,
+        });
+        assert_eq!(summary.regions, vec![500, 500, 0, 1000]);
+        assert_eq!(summary.bytes, 2000);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+
+use std::io::{self, Read};
+
+use rctlib::*;
+
+use crate::formats::Structured;
+use crate::limits::StreamPermit;
+use crate::metrics::Metrics;
+use crate::{
+    blocking, check_loaded, check_ranges, open_vdisk, AuthKeyGuard, DiskContentResponder,
+    QueryStringRanges, ReadConfig, VirtDiskReader,
+};
+
+// Granularity of the ranges included in raw images
+const RAW_BLOCK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum ExportFormat {
+    Qcow2,
+    Vhdx,
+    Raw,
+}
+
+type ExportResult = Result<DiskContentResponder, status::Custom<String>>;
+
+async fn get_disk_export_common(
+    path: String,
+    format: ExportFormat,
+    ranges: Option<Vec<VirtualDiskChangeRange>>,
+    read_config: &ReadConfig,
+    metrics: &Metrics,
+    permit: StreamPermit,
+) -> ExportResult {
+    let read_config = *read_config;
+    let metrics = metrics.clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let disk_size = vdisk.get_virtual_size().unwrap();
+        let ranges = ranges.unwrap_or_else(|| {
+            vec![VirtualDiskChangeRange {
+                offset: 0,
+                length: disk_size,
+            }]
+        });
+        check_ranges(&ranges, disk_size)?;
+
+        check_loaded(&vdisk, &path, &read_config)?;
+        if let Err(e) = vdisk.attach(true) {
+            metrics.disk_attach_failed();
+            panic!("{}", e);
+        }
+        metrics.disk_attached();
+
+        let block_size = match format {
+            ExportFormat::Qcow2 => QCOW2_CLUSTER_SIZE,
+            ExportFormat::Vhdx => VHDX_STREAM_BLOCK_SIZE,
+            ExportFormat::Raw => RAW_BLOCK_SIZE,
+        };
+        let ranges = align_ranges(&ranges, block_size, disk_size);
+        let read_error = |e: io::Error| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Unable to read {}: {}", path, e),
+            )
+        };
+        let (map, reader) = match format {
+            // Raw images include the zeros anyway
+            ExportFormat::Raw => {
+                let mut map = BlockMap::new(block_size, disk_size);
+                map.set_ranges(&ranges);
+                let reader = VirtDiskReader::open(
+                    Box::new(vdisk),
+                    map.ranges(),
+                    &read_config,
+                    metrics,
+                    permit,
+                )
+                .map_err(read_error)?;
+                (map, reader)
+            }
+            // Blocks containing only zeros are found with a first pass over
+            // the ranges, as the image metadata precedes the data. The whole
+            // pass completes before the response headers are sent, which for
+            // large disks takes about as long as the download itself.
+            _ => {
+                let mut reader = VirtDiskReader::open(
+                    Box::new(vdisk),
+                    ranges.clone(),
+                    &read_config,
+                    metrics,
+                    permit,
+                )
+                .map_err(read_error)?;
+                let map =
+                    scan_blocks(&mut reader, &ranges, block_size, disk_size).map_err(read_error)?;
+                reader
+                    .read_ranges(map.ranges(), &read_config)
+                    .map_err(read_error)?;
+                (map, reader)
+            }
+        };
+
+        let ranges_count = reader.ranges_count;
+        let (stream, content_length): (Box<dyn Read + Send>, u64) = match format {
+            ExportFormat::Qcow2 => {
+                let stream = Qcow2Stream::new(map, reader);
+                let content_length = stream.content_length();
+                (Box::new(stream), content_length)
+            }
+            ExportFormat::Vhdx => {
+                let stream = VhdxStream::new(map, reader)
+                    .map_err(|e| status::Custom(Status::BadRequest, e.to_string()))?;
+                let content_length = stream.content_length();
+                (Box::new(stream), content_length)
+            }
+            ExportFormat::Raw => {
+                let stream = RawImageStream::new(map, reader);
+                let content_length = stream.content_length();
+                (Box::new(stream), content_length)
+            }
+        };
+        Ok(DiskContentResponder {
+            reader: stream,
+            content_length: Some(content_length),
+            content_type: "application/octet-stream",
+            headers: Vec::new(),
+            ranges: Some(ranges_count),
+        })
+    })
+    .await
+}
+
+/// Streams an image of the disk as qcow2, vhdx or raw, including only the
+/// given ranges, the whole disk by default. Blocks of zeros are omitted
+/// from the qcow2 and vhdx images.
+#[get("/vdisk/<path>/export?<format>&<ranges>")]
+pub async fn get_disk_export(
+    path: String,
+    format: ExportFormat,
+    ranges: Option<QueryStringRanges>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> ExportResult {
+    let ranges = ranges.map(|r| r.ranges);
+    get_disk_export_common(path, format, ranges, read_config, metrics, permit).await
+}
+
+// Provide a POST alternative to GET due to the query string's length limits
+#[post("/vdisk/<path>/export?<format>", data = "<ranges>")]
+pub async fn get_disk_export_post(
+    path: String,
+    format: ExportFormat,
+    ranges: Structured<Vec<VirtualDiskChangeRange>>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> ExportResult {
+    let ranges = Some(ranges.into_inner());
+    get_disk_export_common(path, format, ranges, read_config, metrics, permit).await
+}
@@ -0,0 +1,200 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use rocket::data::{self, ByteUnit, Data, FromData, Limits};
+use rocket::http::{ContentType, MediaType, Status};
+use rocket::outcome::Outcome::{Error, Forward, Success};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::io::Cursor;
+
+/// Serialization formats of the structured requests and responses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl Format {
+    fn from_media_type(media_type: &MediaType) -> Option<Format> {
+        match (media_type.top().as_str(), media_type.sub().as_str()) {
+            ("application", "json") | ("application", "*") | ("*", "*") => Some(Format::Json),
+            ("application", "cbor") => Some(Format::Cbor),
+            ("application", "msgpack") | ("application", "x-msgpack") => Some(Format::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// The format of the client's most preferred type in the Accept header,
+    /// the first one with the highest weight, JSON if there is no header.
+    /// None if that type is not a supported format, even if a type with a
+    /// lower weight is.
+    pub fn from_accept(request: &Request<'_>) -> Option<Format> {
+        let accept = match request.accept() {
+            Some(accept) => accept,
+            None => return Some(Format::Json),
+        };
+        let mut preferred = None;
+        let mut weight = 0.0;
+        for media_type in accept.iter() {
+            let media_weight = media_type.weight_or(1.0);
+            if media_weight > weight {
+                preferred = Some(media_type.media_type());
+                weight = media_weight;
+            }
+        }
+        preferred.and_then(Format::from_media_type)
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Json => ContentType::JSON,
+            Format::Cbor => ContentType::new("application", "cbor"),
+            Format::MsgPack => ContentType::MsgPack,
+        }
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(value, &mut data).map_err(|e| e.to_string())?;
+                Ok(data)
+            }
+            // Structs as maps, to keep the field names as in JSON
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::de::from_reader(data).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Guard of the routes returning Structured responses, forwarding requests
+/// accepting none of the supported formats to the other routes sharing the
+/// same path, e.g. the NDJSON changes.
+pub struct StructuredFormat;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StructuredFormat {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<StructuredFormat, ()> {
+        match Format::from_accept(request) {
+            Some(_) => Success(StructuredFormat),
+            None => Forward(Status::NotAcceptable),
+        }
+    }
+}
+
+/// Whether the client asked for the content as frames, followed by a
+/// trailer telling whether the disk changed while being read.
+pub struct FramesFormat(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FramesFormat {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<FramesFormat, ()> {
+        let frames = match request.accept() {
+            Some(accept) => {
+                let media_type = accept.preferred().media_type();
+                media_type.top() == "application" && media_type.sub() == "x-rct-frames"
+            }
+            None => false,
+        };
+        Success(FramesFormat(frames))
+    }
+}
+
+/// Response serialized in the format requested in the Accept header, or a
+/// request body in the format given by its Content-Type.
+#[derive(Debug)]
+pub struct Structured<T>(pub T);
+
+impl<T> Structured<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Structured<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let format = Format::from_accept(request).unwrap_or(Format::Json);
+        let data = format.serialize(&self.0).map_err(|e| {
+            error!("Unable to serialize the response: {}", e);
+            Status::InternalServerError
+        })?;
+        Response::build()
+            .header(format.content_type())
+            .sized_body(data.len(), Cursor::new(data))
+            .ok()
+    }
+}
+
+/// Reads a request body in the format given by its Content-Type, up to the
+/// named limit, default_limit if not configured.
+pub async fn read_structured<'r, T: DeserializeOwned>(
+    request: &'r Request<'_>,
+    data: Data<'r>,
+    limit_name: &str,
+    default_limit: ByteUnit,
+) -> data::Outcome<'r, T, String> {
+    let format = match request.content_type() {
+        None => Format::Json,
+        Some(content_type) => match Format::from_media_type(content_type.media_type()) {
+            Some(format) => format,
+            None => return Forward((data, Status::UnsupportedMediaType)),
+        },
+    };
+
+    let limit = request.limits().get(limit_name).unwrap_or(default_limit);
+    let body = match data.open(limit).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            return Error((
+                Status::PayloadTooLarge,
+                format!("The body exceeds the limit of {}", limit),
+            ))
+        }
+        Err(e) => return Error((Status::BadRequest, e.to_string())),
+    };
+    match format.deserialize(&body) {
+        Ok(value) => Success(value),
+        Err(e) => Error((Status::BadRequest, e)),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for Structured<T> {
+    type Error = String;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        // Same limit for all the formats
+        read_structured(request, data, "json", Limits::JSON)
+            .await
+            .map(Structured)
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::{Config, Either, State};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use x509_parser::pem::parse_x509_pem;
+
+use rctlib::*;
+
+use crate::{AuthKeys, DiskDirectories};
+
+/// API features supported by this service, for clients to negotiate capabilities.
+pub const API_FEATURES: &[&str] = &[
+    "info",
+    "unaligned_ranges",
+    "create",
+    "rct",
+    "rct_changes",
+    "rct_changes_content",
+    "disk_changed",
+    "refuse_loaded",
+    "hash_delta",
+    "manifest",
+    "rct_changes_ndjson",
+    "rct_changes_bitmap",
+    "cbor",
+    "msgpack",
+    "rct_changes_summary",
+    "content",
+    "content_post",
+    "restore",
+    "export_qcow2",
+    "export_vhdx",
+    "export_raw",
+    "jobs",
+    "rct_history",
+    "metrics",
+    "health",
+];
+
+/// Settings needed by the readiness checks, captured when the service is configured.
+pub struct ReadyConfig {
+    tls_enabled: bool,
+    tls_certs_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    tls_cert_expiry_days: i64,
+}
+
+impl ReadyConfig {
+    pub fn from_config(figment: &Figment) -> ReadyConfig {
+        let config = Config::from(figment);
+        // Certificates provided inline can't be checked for expiration
+        let tls_certs_path = config.tls.as_ref().and_then(|tls| match tls.certs() {
+            Either::Left(path) => Some(path),
+            Either::Right(_) => None,
+        });
+        let tls_key_path = config.tls.as_ref().and_then(|tls| match tls.key() {
+            Either::Left(path) => Some(path),
+            Either::Right(_) => None,
+        });
+
+        ReadyConfig {
+            tls_enabled: config.tls_enabled(),
+            tls_certs_path,
+            tls_key_path,
+            tls_cert_expiry_days: figment
+                .extract_inner::<i64>("tls_cert_expiry_days")
+                .unwrap_or(14),
+        }
+    }
+}
+
+// Returns the notAfter field of the first certificate in a PEM file
+fn cert_not_after(pem: &[u8]) -> Result<DateTime<Utc>, String> {
+    let (_, pem) = parse_x509_pem(pem).map_err(|e| e.to_string())?;
+    let cert = pem.parse_x509().map_err(|e| e.to_string())?;
+    let not_after = cert.validity().not_after.timestamp();
+    Utc.timestamp_opt(not_after, 0)
+        .single()
+        .ok_or_else(|| format!("Invalid expiration time: {}", not_after))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub applicable: bool,
+    pub message: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<(), String>) -> Check {
+        Check {
+            name: name.to_string(),
+            ok: result.is_ok(),
+            applicable: true,
+            message: result.err(),
+        }
+    }
+
+    // Passes, e.g. for the TLS certificate when TLS is not enabled
+    fn not_applicable(name: &str, message: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            ok: true,
+            applicable: false,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+fn check_config(config: &ReadyConfig, auth_keys: &AuthKeys) -> Result<(), String> {
+    if auth_keys.keys.is_empty() {
+        return Err("No auth keys configured".to_string());
+    }
+    if let Some(key_id) = auth_keys
+        .keys
+        .iter()
+        .find(|(k, _)| k.is_empty())
+        .map(|e| e.1)
+    {
+        return Err(format!("The auth key {} is empty", key_id));
+    }
+    // The files are read when the service starts, they can be replaced since
+    for path in config
+        .tls_certs_path
+        .iter()
+        .chain(config.tls_key_path.iter())
+    {
+        let data =
+            fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        if data.is_empty() {
+            return Err(format!("{} is empty", path.display()));
+        }
+    }
+    Ok(())
+}
+
+fn check_tls(config: &ReadyConfig) -> Result<(), String> {
+    let path = match config.tls_certs_path {
+        Some(ref path) => path,
+        None => return Err("Unable to determine the TLS certificate path".to_string()),
+    };
+    let pem = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let not_after = cert_not_after(&pem).map_err(|e| {
+        format!(
+            "Unable to parse the certificate in {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let days_left = (not_after - Utc::now()).num_days();
+    if days_left < config.tls_cert_expiry_days {
+        return Err(format!(
+            "TLS certificate expires on {} ({} days left)",
+            not_after.to_rfc3339(),
+            days_left
+        ));
+    }
+    Ok(())
+}
+
+fn check_disk_directories(disk_directories: &DiskDirectories) -> Result<(), String> {
+    for directory in disk_directories.directories.iter() {
+        fs::read_dir(directory).map_err(|e| format!("{}: {}", directory.display(), e))?;
+    }
+    Ok(())
+}
+
+fn check_virtdisk() -> Result<(), String> {
+    // Opening a missing disk exercises the virtdisk API without touching any real disk
+    let probe_path = env::temp_dir().join("rct-service-ready-probe.vhdx");
+    match VirtDisk::open(&probe_path.to_string_lossy(), true) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.result() {
+            ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => Ok(()),
+            _ => Err(e.to_string()),
+        },
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+}
+
+#[get("/health")]
+pub fn get_health() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[get("/ready")]
+pub fn get_ready(
+    config: &State<ReadyConfig>,
+    auth_keys: &State<AuthKeys>,
+    disk_directories: &State<DiskDirectories>,
+) -> status::Custom<Json<Readiness>> {
+    let tls = if config.tls_enabled {
+        Check::new("tls", check_tls(config))
+    } else {
+        Check::not_applicable("tls", "TLS is not enabled")
+    };
+    let disk_directories = if disk_directories.directories.is_empty() {
+        Check::not_applicable("disk_directories", "No disk_directories configured")
+    } else {
+        Check::new("disk_directories", check_disk_directories(disk_directories))
+    };
+    let checks = vec![
+        Check::new("config", check_config(config, auth_keys)),
+        tls,
+        disk_directories,
+        Check::new("virtdisk", check_virtdisk()),
+    ];
+    let ready = checks.iter().all(|c| c.ok);
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    status::Custom(status, Json(Readiness { ready, checks }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub target_os: &'static str,
+    pub target_arch: &'static str,
+    pub debug: bool,
+    pub api_features: &'static [&'static str],
+}
+
+#[get("/version")]
+pub fn get_version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        target_os: env::consts::OS,
+        target_arch: env::consts::ARCH,
+        debug: cfg!(debug_assertions),
+        api_features: API_FEATURES,
+    })
+}
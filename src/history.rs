@@ -0,0 +1,226 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! History of the RCT IDs observed for each disk, persisted in a JSON file,
+//! so that a valid baseline can still be found after a client lost track of
+//! its last RCT ID.
+
+use rocket::figment::value::magic::RelativePathBuf;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rctlib::*;
+
+use crate::formats::{Structured, StructuredFormat};
+use crate::jobs::write_json;
+use crate::{blocking, open_vdisk, AuthKeyGuard};
+
+const DEFAULT_MAX_IDS: usize = 100;
+// The file is written once the observations made meanwhile are recorded
+const WRITE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RctHistoryEntry {
+    pub rct_id: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    // Backup jobs that stored the ID, as the baseline of the next backup
+    #[serde(default)]
+    pub jobs: Vec<u64>,
+    // Whether the disk can still return the changes since the ID, only
+    // checked on request
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub valid: Option<bool>,
+}
+
+struct RctHistoryData {
+    path: Option<PathBuf>,
+    max_ids: usize,
+    disks: Mutex<BTreeMap<String, Vec<RctHistoryEntry>>>,
+    write_pending: AtomicBool,
+    // Held while writing the file, so that the last version wins
+    write_lock: Mutex<()>,
+}
+
+/// RCT IDs observed for each disk, oldest first, up to max_ids per disk.
+#[derive(Clone)]
+pub struct RctHistory {
+    data: Arc<RctHistoryData>,
+}
+
+impl RctHistory {
+    pub fn from_config(figment: &Figment) -> RctHistory {
+        let path = figment
+            .extract_inner::<RelativePathBuf>("rct_history")
+            .ok()
+            .map(|p| p.relative());
+        let max_ids = figment
+            .extract_inner::<usize>("rct_history_max_ids")
+            .unwrap_or(DEFAULT_MAX_IDS);
+        let disks = match path {
+            Some(ref path) => match File::open(path) {
+                Ok(file) => serde_json::from_reader(file).unwrap_or_else(|e| {
+                    // Kept aside, as it gets replaced on the next observation
+                    let corrupt_path = path.with_extension("json.corrupt");
+                    warn!(
+                        "Ignoring the corrupt RCT history {}, moved to {}: {}",
+                        path.display(),
+                        corrupt_path.display(),
+                        e
+                    );
+                    if let Err(e) = fs::rename(path, &corrupt_path) {
+                        error!("Failed to move the RCT history: {}", e);
+                    }
+                    BTreeMap::new()
+                }),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => panic!("Unable to open the RCT history: {}", e),
+            },
+            None => BTreeMap::new(),
+        };
+        RctHistory {
+            data: Arc::new(RctHistoryData {
+                path,
+                max_ids: std::cmp::max(max_ids, 1),
+                disks: Mutex::new(disks),
+                write_pending: AtomicBool::new(false),
+                write_lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    // Writes the file after WRITE_DELAY, unless a write is already pending
+    fn schedule_write(&self) {
+        if self.data.write_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let data = self.data.clone();
+        thread::spawn(move || {
+            thread::sleep(WRITE_DELAY);
+            let _write_lock = data.write_lock.lock().unwrap();
+            // Observations recorded from now on schedule another write
+            data.write_pending.store(false, Ordering::SeqCst);
+            let disks = data.disks.lock().unwrap().clone();
+            if let Some(ref path) = data.path {
+                if let Err(e) = write_json(path, &disks) {
+                    error!("Failed to write the RCT history: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Records an RCT ID observed for a disk, and the backup job using it.
+    pub fn record(&self, disk_path: &str, rct_id: &str, job: Option<u64>) {
+        if self.data.path.is_none() {
+            return;
+        }
+        // Disks without RCT enabled report an empty ID
+        if rct_id.is_empty() {
+            return;
+        }
+
+        let now = chrono::Utc::now().to_rfc3339();
+        let mut disks = self.data.disks.lock().unwrap();
+        let entries = disks.entry(disk_path.to_string()).or_default();
+        match entries.iter_mut().find(|e| e.rct_id == rct_id) {
+            Some(entry) => {
+                entry.last_seen = now;
+                if let Some(job) = job {
+                    entry.jobs.push(job);
+                }
+            }
+            None => {
+                entries.push(RctHistoryEntry {
+                    rct_id: rct_id.to_string(),
+                    first_seen: now.clone(),
+                    last_seen: now,
+                    jobs: job.into_iter().collect(),
+                    valid: None,
+                });
+                if entries.len() > self.data.max_ids {
+                    let excess = entries.len() - self.data.max_ids;
+                    entries.drain(..excess);
+                }
+            }
+        }
+        drop(disks);
+        self.schedule_write();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.data.path.is_some()
+    }
+
+    pub fn get(&self, disk_path: &str) -> Vec<RctHistoryEntry> {
+        let disks = self.data.disks.lock().unwrap();
+        disks.get(disk_path).cloned().unwrap_or_default()
+    }
+}
+
+/// Returns the RCT IDs observed for a disk, oldest first. With validate,
+/// each ID is checked against the disk's change tracking information.
+#[get("/vdisk/<path>/rct/history?<validate>")]
+pub async fn get_rct_history(
+    path: String,
+    validate: Option<bool>,
+    history: &State<RctHistory>,
+    _format: StructuredFormat,
+    _key: AuthKeyGuard,
+) -> Result<Structured<Vec<RctHistoryEntry>>, status::Custom<String>> {
+    if !history.is_enabled() {
+        return Err(status::Custom(
+            Status::ServiceUnavailable,
+            "No rct_history configured".to_string(),
+        ));
+    }
+
+    let mut entries = history.get(&path);
+    if validate.unwrap_or(false) {
+        entries = blocking(move || {
+            let vdisk =
+                open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+            let virtual_size = vdisk.get_virtual_size().unwrap();
+            for entry in entries.iter_mut() {
+                // Only the first batch of changes is queried
+                let valid = match ChangesIter::new(&vdisk, &entry.rct_id, 0, virtual_size) {
+                    Ok(_) => true,
+                    Err(ref e) if e.result() == ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION => {
+                        false
+                    }
+                    Err(e) => {
+                        return Err(status::Custom(
+                            Status::InternalServerError,
+                            format!("Unable to check the RCT ID {}: {}", entry.rct_id, e),
+                        ))
+                    }
+                };
+                entry.valid = Some(valid);
+            }
+            Ok(entries)
+        })
+        .await?;
+    }
+    Ok(Structured(entries))
+}
@@ -0,0 +1,451 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Backup jobs, storing full and incremental backups of virtual disks in a
+//! local repository. Each disk has a directory with a state file tracking
+//! the last RCT ID, and each backup is stored as a change set: a JSON file
+//! with the ranges and a file with their content.
+
+use ring::digest;
+
+use rocket::figment::value::magic::RelativePathBuf;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::response::status::{self, NotFound};
+use rocket::serde::json::Json;
+use rocket::tokio::task;
+use rocket::State;
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rctlib::*;
+
+use crate::history::RctHistory;
+use crate::limits::StreamPermit;
+use crate::metrics::Metrics;
+use crate::{check_loaded, disk_changed, AuthKeyGuard, ReadConfig, VirtDiskReader};
+
+const STATE_FILE: &str = "state.json";
+// Finished jobs kept for /jobs, the oldest are dropped first
+const MAX_FINISHED_JOBS: usize = 1000;
+const COPY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupType {
+    Full,
+    Incremental,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub path: String,
+    pub status: JobStatus,
+    /// Known once the job has checked the disk's previous backups
+    pub backup_type: Option<BackupType>,
+    /// Name of the backup in the disk's repository directory
+    pub backup: Option<String>,
+    pub rct_id: Option<String>,
+    pub ranges: usize,
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    /// Whether the disk was written while being backed up, if known
+    pub disk_changed: Option<bool>,
+    pub started: String,
+    pub finished: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    pub path: String,
+    /// Incremental if possible by default, falling back to full
+    #[serde(default)]
+    pub backup_type: Option<BackupType>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskState {
+    path: String,
+    last_rct_id: Option<String>,
+    backups: Vec<BackupRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupRecord {
+    name: String,
+    backup_type: BackupType,
+    rct_id: Option<String>,
+    timestamp: String,
+    ranges: usize,
+    bytes: u64,
+    sha256: String,
+    #[serde(default)]
+    disk_changed: Option<bool>,
+}
+
+struct JobsData {
+    repository: Option<PathBuf>,
+    history: RctHistory,
+    jobs: Mutex<VecDeque<Job>>,
+    next_id: Mutex<u64>,
+}
+
+/// Backup jobs started by this service, running in the blocking thread pool.
+#[derive(Clone)]
+pub struct Jobs {
+    data: Arc<JobsData>,
+}
+
+// Directory name of a disk in the repository: its file name, readable, and
+// a hash of its full path, unique
+pub fn disk_directory(path: &str) -> String {
+    let name: String = path
+        .rsplit(&['/', '\\'][..])
+        .next()
+        .unwrap_or("")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-' || *c == '_')
+        .collect();
+    let hash = hex_digest(&digest::digest(&digest::SHA256, path.as_bytes()));
+    format!("{}-{}", name, &hash[..16])
+}
+
+// Writes a JSON file atomically, replacing any previous version
+pub fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&temp_path)?;
+    serde_json::to_writer_pretty(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+fn panic_message(e: Box<dyn std::any::Any + Send>) -> String {
+    match e.downcast::<String>() {
+        Ok(message) => *message,
+        Err(e) => match e.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "The job panicked".to_string(),
+        },
+    }
+}
+
+impl Jobs {
+    pub fn from_config(figment: &Figment, history: RctHistory) -> Jobs {
+        let repository = figment
+            .extract_inner::<RelativePathBuf>("backup_repository")
+            .ok()
+            .map(|r| r.relative());
+        Jobs {
+            data: Arc::new(JobsData {
+                repository,
+                history,
+                jobs: Mutex::new(VecDeque::new()),
+                next_id: Mutex::new(1),
+            }),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: u64, f: F) {
+        let mut jobs = self.data.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == id) {
+            f(job);
+        }
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.data.jobs.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.list().into_iter().find(|j| j.id == id)
+    }
+
+    // Registers a new job, unless one is already running for the same disk
+    fn add(&self, path: &str) -> Option<Job> {
+        let mut jobs = self.data.jobs.lock().unwrap();
+        if jobs
+            .iter()
+            .any(|j| j.path == path && j.status == JobStatus::Running)
+        {
+            return None;
+        }
+        while jobs.len() >= MAX_FINISHED_JOBS {
+            match jobs.iter().position(|j| j.status != JobStatus::Running) {
+                Some(index) => jobs.remove(index),
+                None => break,
+            };
+        }
+
+        let mut next_id = self.data.next_id.lock().unwrap();
+        let job = Job {
+            id: *next_id,
+            path: path.to_string(),
+            status: JobStatus::Running,
+            backup_type: None,
+            backup: None,
+            rct_id: None,
+            ranges: 0,
+            bytes_total: 0,
+            bytes_done: 0,
+            disk_changed: None,
+            started: chrono::Utc::now().to_rfc3339(),
+            finished: None,
+            error: None,
+        };
+        *next_id += 1;
+        jobs.push_back(job.clone());
+        Some(job)
+    }
+
+    fn run(
+        &self,
+        id: u64,
+        request: &JobRequest,
+        read_config: &ReadConfig,
+        metrics: Metrics,
+        permit: StreamPermit,
+    ) -> Result<(), String> {
+        let repository = self.data.repository.as_ref().unwrap();
+        let vdisk = VirtDisk::open(&request.path, true).map_err(|e| e.to_string())?;
+        // Captured before querying the changes, so that later writes are
+        // included in the next backup
+        let rct_info = vdisk.get_rct_info().map_err(|e| e.to_string())?;
+
+        let directory = repository.join(disk_directory(&request.path));
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+        let state_path = directory.join(STATE_FILE);
+        let mut state = match File::open(&state_path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| e.to_string())?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => DiskState {
+                path: request.path.clone(),
+                last_rct_id: None,
+                backups: Vec::new(),
+            },
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let changes = match state.last_rct_id {
+            Some(ref last_rct_id)
+                if rct_info.enabled && request.backup_type != Some(BackupType::Full) =>
+            {
+                let start = Instant::now();
+                let changes = vdisk.query_changes(last_rct_id);
+                metrics.query_changes_done(start.elapsed(), changes.as_ref().ok().map(|c| c.len()));
+                match changes {
+                    Ok(changes) => Some(changes),
+                    // The previous ID is no longer valid, e.g. after RCT was reset
+                    Err(ref e) if e.result() == ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION => {
+                        None
+                    }
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+            _ => None,
+        };
+        if changes.is_none() && request.backup_type == Some(BackupType::Incremental) {
+            return Err(format!(
+                "No valid previous backup for an incremental backup of {}",
+                request.path
+            ));
+        }
+        let backup_type = match changes {
+            Some(_) => BackupType::Incremental,
+            None => BackupType::Full,
+        };
+        let ranges = changes.unwrap_or_else(|| {
+            vec![VirtualDiskChangeRange {
+                offset: 0,
+                length: vdisk.get_virtual_size().unwrap(),
+            }]
+        });
+        let rct_id = if rct_info.enabled {
+            Some(rct_info.most_recent_id.clone())
+        } else {
+            None
+        };
+
+        let name = format!("{:06}", state.backups.len() + 1);
+        let data_path = directory.join(format!("{}.bin", name));
+        let bytes_total = ranges.iter().map(|r| r.length).sum();
+        self.update(id, |job| {
+            job.backup_type = Some(backup_type);
+            job.backup = Some(name.clone());
+            job.rct_id = rct_id.clone();
+            job.ranges = ranges.len();
+            job.bytes_total = bytes_total;
+        });
+
+        check_loaded(&vdisk, &request.path, read_config).map_err(|e| e.1)?;
+        if let Err(e) = vdisk.attach(true) {
+            metrics.disk_attach_failed();
+            return Err(e.to_string());
+        }
+        metrics.disk_attached();
+        let mut reader = VirtDiskReader::open(
+            Box::new(vdisk),
+            ranges.clone(),
+            read_config,
+            metrics,
+            permit,
+        )
+        .map_err(|e| format!("Unable to read {}: {}", request.path, e))?;
+
+        // The data is complete only once renamed
+        let partial_path = data_path.with_extension("bin.partial");
+        let sha256 = (|| -> io::Result<String> {
+            let mut file = File::create(&partial_path)?;
+            let mut digest = digest::Context::new(&digest::SHA256);
+            let mut buf = vec![0u8; COPY_SIZE];
+            loop {
+                let read = reader.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                file.write_all(&buf[..read])?;
+                digest.update(&buf[..read]);
+                self.update(id, |job| job.bytes_done += read as u64);
+            }
+            file.sync_all()?;
+            fs::rename(&partial_path, &data_path)?;
+            Ok(hex_digest(&digest.finish()))
+        })()
+        .map_err(|e| {
+            let _ = fs::remove_file(&partial_path);
+            e.to_string()
+        })?;
+        let rct_info_end = reader
+            .get_virt_disk()
+            .get_rct_info()
+            .map_err(|e| e.to_string())?;
+        drop(reader);
+        let disk_changed = disk_changed(&rct_info, &rct_info_end);
+        self.update(id, |job| job.disk_changed = disk_changed);
+
+        let change_set = ChangeSet {
+            ranges,
+            data_path: data_path.to_string_lossy().to_string(),
+            sha256: Some(sha256.clone()),
+        };
+        write_json(&directory.join(format!("{}.json", name)), &change_set)
+            .map_err(|e| e.to_string())?;
+
+        state.backups.push(BackupRecord {
+            name,
+            backup_type,
+            rct_id: rct_id.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            ranges: change_set.ranges.len(),
+            bytes: bytes_total,
+            sha256,
+            disk_changed,
+        });
+        if let Some(ref rct_id) = rct_id {
+            self.data.history.record(&request.path, rct_id, Some(id));
+        }
+        state.last_rct_id = rct_id;
+        write_json(&state_path, &state).map_err(|e| e.to_string())
+    }
+
+    pub fn start(
+        &self,
+        request: JobRequest,
+        read_config: &ReadConfig,
+        metrics: &Metrics,
+        permit: StreamPermit,
+    ) -> Result<Job, status::Custom<String>> {
+        if self.data.repository.is_none() {
+            return Err(status::Custom(
+                Status::ServiceUnavailable,
+                "No backup_repository configured".to_string(),
+            ));
+        }
+        let job = self.add(&request.path).ok_or_else(|| {
+            status::Custom(
+                Status::Conflict,
+                format!("A job is already running for {}", request.path),
+            )
+        })?;
+
+        let jobs = self.clone();
+        let id = job.id;
+        let read_config = *read_config;
+        let metrics = metrics.clone();
+        task::spawn_blocking(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                jobs.run(id, &request, &read_config, metrics, permit)
+            }));
+            let error = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e),
+                Err(e) => Some(panic_message(e)),
+            };
+            jobs.update(id, |job| {
+                job.status = match error {
+                    Some(_) => JobStatus::Failed,
+                    None => JobStatus::Succeeded,
+                };
+                job.error = error;
+                job.finished = Some(chrono::Utc::now().to_rfc3339());
+            });
+        });
+        Ok(job)
+    }
+}
+
+/// Starts a backup of a disk to the repository, incremental if a previous
+/// backup's RCT ID is still valid, full otherwise.
+#[post("/jobs", format = "application/json", data = "<request>")]
+pub async fn create_job(
+    request: Json<JobRequest>,
+    jobs: &State<Jobs>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> Result<status::Accepted<Json<Job>>, status::Custom<String>> {
+    let job = jobs.start(request.into_inner(), read_config, metrics, permit)?;
+    Ok(status::Accepted(Json(job)))
+}
+
+#[get("/jobs", format = "json")]
+pub fn get_jobs(jobs: &State<Jobs>, _key: AuthKeyGuard) -> Json<Vec<Job>> {
+    Json(jobs.list())
+}
+
+#[get("/jobs/<id>", format = "json")]
+pub fn get_job(
+    id: u64,
+    jobs: &State<Jobs>,
+    _key: AuthKeyGuard,
+) -> Result<Json<Job>, NotFound<String>> {
+    jobs.get(id)
+        .map(Json)
+        .ok_or_else(|| NotFound(format!("Job not found: {}", id)))
+}
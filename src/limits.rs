@@ -0,0 +1,332 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use chrono::{Local, NaiveTime};
+
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::outcome::Outcome::{Error, Success};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::State;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::AuthKeyGuard;
+
+#[derive(Clone, Copy)]
+struct Limit {
+    // 0 means unlimited
+    max_streams: u64,
+    bandwidth: u64,
+}
+
+// Per key entry of the key_limits config table
+#[derive(Deserialize)]
+struct KeyLimitConfig {
+    max_streams: Option<u64>,
+    bandwidth_limit: Option<u64>,
+}
+
+impl Limit {
+    fn from_config(config: &KeyLimitConfig, default: Limit) -> Limit {
+        Limit {
+            max_streams: config.max_streams.unwrap_or(default.max_streams),
+            bandwidth: config.bandwidth_limit.unwrap_or(default.bandwidth),
+        }
+    }
+}
+
+// Entry of the bandwidth_windows config array
+#[derive(Deserialize)]
+struct BandwidthWindowConfig {
+    start: String,
+    end: String,
+    bandwidth_limit: Option<u64>,
+    bandwidth_limit_per_key: Option<u64>,
+}
+
+// A time of the day in which different bandwidth limits apply
+struct BandwidthWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    bandwidth: u64,
+    bandwidth_per_key: u64,
+}
+
+impl BandwidthWindow {
+    fn is_active(&self, now: NaiveTime) -> bool {
+        if self.start <= self.end {
+            now >= self.start && now < self.end
+        } else {
+            // The window spans midnight
+            now >= self.start || now < self.end
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> TokenBucket {
+        TokenBucket {
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Takes bytes from the bucket, returning how long the caller needs to wait
+    // for the bucket to pay back any debt. Bursts are limited to one second.
+    fn take(&mut self, bytes: u64, rate: u64) -> Duration {
+        if rate == 0 {
+            return Duration::from_secs(0);
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+struct LimitsState {
+    streams: u64,
+    streams_per_key: HashMap<String, u64>,
+}
+
+struct StreamLimitsData {
+    global: Limit,
+    per_key: Limit,
+    key_overrides: HashMap<String, Limit>,
+    windows: Vec<BandwidthWindow>,
+    retry_after: u64,
+    state: Mutex<LimitsState>,
+    global_bucket: Mutex<TokenBucket>,
+    key_buckets: Mutex<HashMap<String, Arc<Mutex<TokenBucket>>>>,
+}
+
+/// Concurrent streams and bandwidth limits, global and per auth key.
+#[derive(Clone)]
+pub struct StreamLimits {
+    data: Arc<StreamLimitsData>,
+}
+
+impl StreamLimits {
+    pub fn from_config(config: &Figment) -> StreamLimits {
+        let get = |name: &str| config.extract_inner::<u64>(name).unwrap_or(0);
+        let global = Limit {
+            max_streams: get("max_streams"),
+            bandwidth: get("bandwidth_limit"),
+        };
+        let per_key = Limit {
+            max_streams: get("max_streams_per_key"),
+            bandwidth: get("bandwidth_limit_per_key"),
+        };
+
+        let mut key_overrides = HashMap::new();
+        if config.contains("key_limits") {
+            let key_limits = config
+                .extract_inner::<HashMap<String, KeyLimitConfig>>("key_limits")
+                .expect("key_limits values must be tables of integer limits");
+            for (key_id, value) in key_limits {
+                key_overrides.insert(key_id, Limit::from_config(&value, per_key));
+            }
+        }
+
+        let parse_time = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M")
+                .expect("bandwidth_windows start and end must be in the HH:MM format")
+        };
+        let windows = config
+            .extract_inner::<Vec<BandwidthWindowConfig>>("bandwidth_windows")
+            .map_or(Vec::new(), |windows| {
+                windows
+                    .iter()
+                    .map(|w| BandwidthWindow {
+                        start: parse_time(&w.start),
+                        end: parse_time(&w.end),
+                        bandwidth: w.bandwidth_limit.unwrap_or(global.bandwidth),
+                        bandwidth_per_key: w.bandwidth_limit_per_key.unwrap_or(per_key.bandwidth),
+                    })
+                    .collect()
+            });
+
+        StreamLimits {
+            data: Arc::new(StreamLimitsData {
+                global,
+                per_key,
+                key_overrides,
+                windows,
+                retry_after: config.extract_inner::<u64>("retry_after").unwrap_or(30),
+                state: Mutex::new(LimitsState {
+                    streams: 0,
+                    streams_per_key: HashMap::new(),
+                }),
+                global_bucket: Mutex::new(TokenBucket::new()),
+                key_buckets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    fn key_limit(&self, key_id: &str) -> Limit {
+        *self
+            .data
+            .key_overrides
+            .get(key_id)
+            .unwrap_or(&self.data.per_key)
+    }
+
+    // Returns the global and per key bandwidth limits currently in effect
+    fn bandwidth(&self, key_id: &str) -> (u64, u64) {
+        let key_limit = self.key_limit(key_id);
+        let now = Local::now().time();
+        match self.data.windows.iter().find(|w| w.is_active(now)) {
+            // Per key overrides take precedence over the windows' per key limit
+            Some(w) if self.data.key_overrides.contains_key(key_id) => {
+                (w.bandwidth, key_limit.bandwidth)
+            }
+            Some(w) => (w.bandwidth, w.bandwidth_per_key),
+            None => (self.data.global.bandwidth, key_limit.bandwidth),
+        }
+    }
+
+    pub fn acquire(&self, key_id: &str) -> Option<StreamPermit> {
+        let key_limit = self.key_limit(key_id);
+        let mut state = self.data.state.lock().unwrap();
+        let key_streams = state.streams_per_key.get(key_id).cloned().unwrap_or(0);
+
+        if (self.data.global.max_streams > 0 && state.streams >= self.data.global.max_streams)
+            || (key_limit.max_streams > 0 && key_streams >= key_limit.max_streams)
+        {
+            return None;
+        }
+
+        state.streams += 1;
+        state
+            .streams_per_key
+            .insert(key_id.to_string(), key_streams + 1);
+
+        let key_bucket = self
+            .data
+            .key_buckets
+            .lock()
+            .unwrap()
+            .entry(key_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new())))
+            .clone();
+
+        Some(StreamPermit {
+            limits: self.clone(),
+            key_id: key_id.to_string(),
+            key_bucket,
+        })
+    }
+
+    fn release(&self, key_id: &str) {
+        let mut state = self.data.state.lock().unwrap();
+        state.streams -= 1;
+        let key_streams = state.streams_per_key.get(key_id).cloned().unwrap_or(1) - 1;
+        if key_streams == 0 {
+            state.streams_per_key.remove(key_id);
+        } else {
+            state
+                .streams_per_key
+                .insert(key_id.to_string(), key_streams);
+        }
+    }
+}
+
+/// Slot in the concurrent streams limits, held until the stream is done.
+pub struct StreamPermit {
+    limits: StreamLimits,
+    key_id: String,
+    key_bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl StreamPermit {
+    /// Accounts for bytes read, sleeping as needed to honor the bandwidth limits.
+    pub fn throttle(&self, bytes: u64) {
+        let (bandwidth, bandwidth_per_key) = self.limits.bandwidth(&self.key_id);
+        let global_wait = self
+            .limits
+            .data
+            .global_bucket
+            .lock()
+            .unwrap()
+            .take(bytes, bandwidth);
+        let key_wait = self
+            .key_bucket
+            .lock()
+            .unwrap()
+            .take(bytes, bandwidth_per_key);
+        let wait = std::cmp::max(global_wait, key_wait);
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.limits.release(&self.key_id);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StreamPermit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<StreamPermit, ()> {
+        let key = try_outcome!(request.guard::<AuthKeyGuard>().await);
+        let limits = try_outcome!(request.guard::<&State<StreamLimits>>().await);
+        match limits.acquire(&key.key_id) {
+            Some(permit) => Success(permit),
+            None => Error((Status::TooManyRequests, ())),
+        }
+    }
+}
+
+pub struct TooManyRequests {
+    retry_after: u64,
+}
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .status(Status::TooManyRequests)
+            .raw_header("Retry-After", self.retry_after.to_string())
+            .ok()
+    }
+}
+
+#[catch(429)]
+pub fn too_many_requests(request: &Request) -> TooManyRequests {
+    let retry_after = request
+        .rocket()
+        .state::<StreamLimits>()
+        .map_or(30, |limits| limits.data.retry_after);
+    TooManyRequests { retry_after }
+}
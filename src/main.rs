@@ -12,130 +12,335 @@
 // License for the specific language governing permissions and limitations
 // under the License.
 
-#![feature(proc_macro_hygiene, decl_macro, result_map_or_else)]
-
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate rocket;
-extern crate rocket_contrib;
 
 extern crate rctlib;
 
+mod audit;
+mod changes;
+mod export;
+mod formats;
+mod health;
+mod history;
+mod jobs;
+mod limits;
+mod manifest;
+mod metrics;
+mod restore;
+#[cfg(all(test, unix))]
+mod tests;
+
 use rocket::fairing::AdHoc;
-use rocket::http::RawStr;
+use rocket::figment::value::magic::RelativePathBuf;
+use rocket::figment::Figment;
+use rocket::form::{self, FromFormField, ValueField};
 use rocket::http::Status;
-use rocket::outcome::Outcome::{Failure, Success};
-use rocket::request::FromFormValue;
+use rocket::outcome::try_outcome;
+use rocket::outcome::Outcome::{Error, Success};
 use rocket::request::{self, FromRequest, Request};
-use rocket::response::status::NotFound;
+use rocket::response::content;
+use rocket::response::status::{self, NotFound};
 use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::tokio::io::{AsyncRead, ReadBuf};
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task;
 use rocket::State;
-use rocket_contrib::json::Json;
+use rocket::{Build, Rocket};
 
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::{self, Read, SeekFrom};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
 use rctlib::*;
 
-const CHUNK_SIZE: u64 = 16 * 1024;
+use audit::{AuditDetails, AuditFairing, AuditLog};
+use changes::FramedContent;
+use formats::{FramesFormat, Structured, StructuredFormat};
+use health::ReadyConfig;
+use history::RctHistory;
+use jobs::Jobs;
+use limits::{StreamLimits, StreamPermit};
+use manifest::ManifestStore;
+use metrics::{Metrics, MetricsFairing};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Chunks queued between the blocking disk reader and the async response body
+const STREAM_QUEUE_SIZE: usize = 2;
 
 #[derive(Debug)]
-struct AuthKey {
-    pub auth_key: String,
+struct AuthKeys {
+    // Maps each accepted key to its key ID
+    keys: HashMap<String, String>,
 }
 
+// Key ID of the authenticated client, cached for the request's lifetime
+struct ClientKeyId(Option<String>);
+
 #[derive(Debug)]
-struct AuthKeyGuard {}
+struct AuthKeyGuard {
+    pub key_id: String,
+}
 
-impl<'a, 'r> FromRequest<'a, 'r> for AuthKeyGuard {
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthKeyGuard {
     type Error = ();
 
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<AuthKeyGuard, ()> {
-        let auth_key_state = request.guard::<State<AuthKey>>()?;
-        if request.headers().get_one("auth_key") == Some(&auth_key_state.auth_key) {
-            Success(AuthKeyGuard {})
-        } else {
-            Failure((Status::Unauthorized, ()))
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<AuthKeyGuard, ()> {
+        let auth_keys = try_outcome!(request.guard::<&State<AuthKeys>>().await);
+        let metrics = try_outcome!(request.guard::<&State<Metrics>>().await);
+        let client_key_id = request.local_cache(|| {
+            let key_id = request
+                .headers()
+                .get_one("auth_key")
+                .and_then(|k| auth_keys.keys.get(k).cloned());
+            if key_id.is_none() {
+                metrics.auth_failed();
+            }
+            ClientKeyId(key_id)
+        });
+        match client_key_id.0 {
+            Some(ref key_id) => Success(AuthKeyGuard {
+                key_id: key_id.clone(),
+            }),
+            None => Error((Status::Unauthorized, ())),
         }
     }
 }
 
+// Runs blocking virtual disk operations in the blocking thread pool,
+// propagating panics to the caller
+async fn blocking<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+// Settings of the disk reads performed by VirtDiskReader
+#[derive(Debug, Clone, Copy)]
+struct ReadConfig {
+    read_size: usize,
+    read_ahead: usize,
+    // Refuses reads of the disks in use, e.g. by a running VM
+    refuse_loaded: bool,
+}
+
 struct VirtDiskReader {
+    // Declared before the virtual disk, so that the read ahead thread is
+    // stopped before the disk gets detached
+    reader: RangeReader,
     // Needed to make sure the virtual disk doesn't get detached until we are done
-    _virt_disk: Box<VirtDisk>,
-    ranges: Vec<VirtualDiskChangeRange>,
-    reader: File,
-    current_range_index: usize,
-    bytes_read: u64,
+    virt_disk: Box<VirtDisk>,
+    ranges_count: usize,
+    started: Instant,
+    metrics: Metrics,
+    permit: StreamPermit,
 }
 
-impl<'a> VirtDiskReader {
-    pub fn new(virt_disk: Box<VirtDisk>, ranges: Vec<VirtualDiskChangeRange>) -> VirtDiskReader {
-        let path = virt_disk.get_physical_disk_path().unwrap();
-        // Using BufReader would fail with the following error when reaching the end of the disk:
-        // Os { code: 27, kind: Other, message: "The drive cannot find the sector requested." }.
-        let mut reader = File::open(path).unwrap();
+fn open_range_reader(
+    virt_disk: &VirtDisk,
+    ranges: &[VirtualDiskChangeRange],
+    read_config: &ReadConfig,
+) -> io::Result<RangeReader> {
+    let path = virt_disk
+        .get_physical_disk_path()
+        .map_err(io::Error::other)?;
+    let disk_size = virt_disk.get_virtual_size().map_err(io::Error::other)?;
+    let sector_size = virt_disk.get_sector_size().map_err(io::Error::other)?;
+    // RangeReader never reads past the end of the disk, which would fail with:
+    // Os { code: 27, kind: Other, message: "The drive cannot find the sector requested." }.
+    let file = File::open(path)?;
+    Ok(RangeReader::new(
+        file,
+        ranges,
+        disk_size,
+        read_config.read_size,
+        read_config.read_ahead,
+        sector_size,
+    ))
+}
 
-        if ranges.len() > 0 {
-            let offset = ranges[0].offset;
-            reader.seek(SeekFrom::Start(offset)).unwrap();
-        }
+impl VirtDiskReader {
+    pub fn open(
+        virt_disk: Box<VirtDisk>,
+        ranges: Vec<VirtualDiskChangeRange>,
+        read_config: &ReadConfig,
+        metrics: Metrics,
+        permit: StreamPermit,
+    ) -> io::Result<VirtDiskReader> {
+        let reader = open_range_reader(&virt_disk, &ranges, read_config)?;
+
+        metrics.stream_started();
+        Ok(VirtDiskReader {
+            reader,
+            virt_disk,
+            ranges_count: ranges.len(),
+            started: Instant::now(),
+            metrics,
+            permit,
+        })
+    }
 
-        VirtDiskReader {
-            _virt_disk: virt_disk,
-            reader: reader,
-            ranges: ranges,
-            current_range_index: 0,
-            bytes_read: 0,
-        }
+    /// Continues with another list of ranges, after a first pass over the
+    /// disk. Both passes count towards the stream's bandwidth and metrics.
+    pub fn read_ranges(
+        &mut self,
+        ranges: Vec<VirtualDiskChangeRange>,
+        read_config: &ReadConfig,
+    ) -> io::Result<()> {
+        let reader = open_range_reader(&self.virt_disk, &ranges, read_config)?;
+        let previous = std::mem::replace(&mut self.reader, reader);
+        self.metrics
+            .observe_stream(previous.stats(), self.started.elapsed());
+        self.ranges_count = ranges.len();
+        self.started = Instant::now();
+        Ok(())
     }
 
     pub fn get_content_length(&self) -> u64 {
-        self.ranges.iter().map(|x| x.length).sum()
+        self.reader.content_length()
+    }
+
+    pub fn get_virt_disk(&self) -> &VirtDisk {
+        &self.virt_disk
     }
 }
 
 impl Read for VirtDiskReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.ranges.len() == 0 {
-            return Ok(0);
+        let read = self.reader.read(buf)?;
+        self.metrics.add_bytes_streamed(read as u64);
+        self.permit.throttle(read as u64);
+        Ok(read)
+    }
+}
+
+impl Drop for VirtDiskReader {
+    fn drop(&mut self) {
+        // The virtual disk is detached when its handle is closed
+        self.metrics.stream_finished();
+        self.metrics.disk_detached();
+        self.metrics
+            .observe_stream(self.reader.stats(), self.started.elapsed());
+    }
+}
+
+// Async response body fed by a VirtDiskReader running in the blocking
+// thread pool. The bounded queue provides backpressure: the reader blocks
+// as long as the client doesn't keep up.
+struct DiskContentStream {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+}
+
+impl DiskContentStream {
+    fn new(mut reader: Box<dyn Read + Send>) -> DiskContentStream {
+        let (sender, receiver) = mpsc::channel(STREAM_QUEUE_SIZE);
+        task::spawn_blocking(move || loop {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => {
+                    buf.truncate(read);
+                    // The receiver is gone if the client disconnected
+                    if sender.blocking_send(Ok(buf)).is_err() {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(e));
+                    break;
+                }
+            }
+        });
+
+        DiskContentStream {
+            receiver,
+            chunk: Vec::new(),
+            chunk_pos: 0,
         }
+    }
+}
 
-        let length = loop {
-            let range_length = self.ranges[self.current_range_index].length;
-            let length = std::cmp::min(buf.len() as u64, range_length - self.bytes_read);
-
-            if length == 0 && self.current_range_index + 1 < self.ranges.len() {
-                self.current_range_index += 1;
-                self.bytes_read = 0;
-                let offset = self.ranges[self.current_range_index].offset;
-                self.reader.seek(SeekFrom::Start(offset)).unwrap();
-            } else {
-                break length;
+impl AsyncRead for DiskContentStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.chunk_pos == self.chunk.len() {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                // End of the content
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
             }
-        };
+        }
 
-        let read = self.reader.read(&mut buf[0..length as usize])?;
-        self.bytes_read += read as u64;
-        Ok(read)
+        let length = std::cmp::min(buf.remaining(), self.chunk.len() - self.chunk_pos);
+        let chunk_pos = self.chunk_pos;
+        buf.put_slice(&self.chunk[chunk_pos..chunk_pos + length]);
+        self.chunk_pos += length;
+        Poll::Ready(Ok(()))
     }
 }
 
 struct DiskContentResponder {
-    reader: Box<VirtDiskReader>,
-}
-
-impl<'r> Responder<'r> for DiskContentResponder {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
-        let content_length = self.reader.get_content_length();
-        // Using CHUNK_SIZE to optimize disk reads
-        Response::build()
-            .chunked_body(self.reader, CHUNK_SIZE)
-            .raw_header("Content-Length", content_length.to_string())
-            .raw_header("Content-Type", "application/octet-stream")
-            .ok()
+    reader: Box<dyn Read + Send>,
+    // Sent chunked when not known upfront
+    content_length: Option<u64>,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    // Not known upfront for streamed changes
+    ranges: Option<usize>,
+}
+
+impl DiskContentResponder {
+    fn new(reader: VirtDiskReader) -> DiskContentResponder {
+        DiskContentResponder {
+            content_length: Some(reader.get_content_length()),
+            content_type: "application/octet-stream",
+            headers: Vec::new(),
+            ranges: Some(reader.ranges_count),
+            reader: Box::new(reader),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for DiskContentResponder {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let ranges = self.ranges;
+        request.local_cache(|| AuditDetails { ranges });
+        // Disk reads are performed separately by RangeReader, CHUNK_SIZE only
+        // affects the size of the chunks sent to the client
+        let mut response = Response::build();
+        response
+            .streamed_body(DiskContentStream::new(self.reader))
+            .raw_header("Content-Type", self.content_type);
+        if let Some(content_length) = self.content_length {
+            response.raw_header("Content-Length", content_length.to_string());
+        }
+        for (name, value) in self.headers {
+            response.raw_header(name, value);
+        }
+        response.ok()
     }
 }
 
@@ -145,149 +350,548 @@ struct VirtDiskInfo {
     pub parent_path: Option<String>,
     pub virtual_storage_type: u32,
     pub provider_sub_type: u32,
+    pub sector_size: u64,
 }
 
 struct QueryStringRanges {
     ranges: Vec<VirtualDiskChangeRange>,
 }
 
-impl<'v> FromFormValue<'v> for QueryStringRanges {
-    type Error = &'v RawStr;
-
-    fn from_form_value(form_value: &'v RawStr) -> Result<QueryStringRanges, &'v RawStr> {
+impl<'v> FromFormField<'v> for QueryStringRanges {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, QueryStringRanges> {
         let mut ranges: Vec<VirtualDiskChangeRange> = Vec::new();
-        for s in form_value.split(",") {
+        for s in field.value.split(",") {
             let v = s
                 .split(":")
                 .map(|x| x.parse::<u64>().unwrap())
                 .collect::<Vec<_>>();
             if v.len() > 2 {
-                return Err("Too many values separated by :".into());
+                return Err(form::Error::validation("Too many values separated by :").into());
             }
             ranges.push(VirtualDiskChangeRange {
                 offset: v[0],
                 length: v[1],
             });
         }
-        Ok(QueryStringRanges { ranges: ranges })
+        Ok(QueryStringRanges { ranges })
     }
 }
 
 fn open_vdisk(path: &str, read_only: bool) -> Result<VirtDisk, NotFound<String>> {
-    VirtDisk::open(&path, read_only).map_err(|e| match e.result() {
+    VirtDisk::open(path, read_only).map_err(|e| match e.result() {
         ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => {
             NotFound(format!("Bad vdisk path: {}", path))
         }
-        _ => panic!(e),
+        _ => panic!("{}", e),
     })
 }
 
-#[get("/vdisk/<path>/info", format = "json")]
-fn get_disk_info(path: String, _key: AuthKeyGuard) -> Result<Json<VirtDiskInfo>, NotFound<String>> {
-    let vdisk = open_vdisk(&path, true)?;
+// Checkpoints are stored next to their parent, as AVHDX or AVHD files
+fn is_checkpoint_parent(path: &str) -> bool {
+    let path = Path::new(path);
+    let canonical_path = match fs::canonicalize(path) {
+        Ok(canonical_path) => canonical_path,
+        Err(_) => return false,
+    };
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let checkpoint_path = entry.path();
+        let extension = checkpoint_path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        let is_checkpoint = matches!(extension.as_deref(), Some("avhdx") | Some("avhd"));
+        is_checkpoint
+            && VirtDisk::open(&checkpoint_path.to_string_lossy(), true)
+                .and_then(|checkpoint| checkpoint.get_parent_path())
+                .ok()
+                .and_then(|parent_path| fs::canonicalize(directory.join(parent_path)).ok())
+                .as_ref()
+                == Some(&canonical_path)
+    })
+}
+
+// With refuse_loaded_disks, disks in use can only be read if they are the
+// parent of a checkpoint, not written anymore
+fn check_loaded(
+    vdisk: &VirtDisk,
+    path: &str,
+    read_config: &ReadConfig,
+) -> Result<(), status::Custom<String>> {
+    if !read_config.refuse_loaded {
+        return Ok(());
+    }
+    let loaded = vdisk.is_loaded().map_err(|e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("Unable to check whether {} is in use: {}", path, e),
+        )
+    })?;
+    if loaded && !is_checkpoint_parent(path) {
+        return Err(status::Custom(
+            Status::Conflict,
+            format!("The disk is in use: {}", path),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the disk was written between two of its RCT states, unknown if
+/// RCT is disabled, or if changes newer than the RCT ID were already pending.
+fn disk_changed(start: &RCTInfo, end: &RCTInfo) -> Option<bool> {
+    if !start.enabled || !end.enabled {
+        None
+    } else if start.most_recent_id != end.most_recent_id
+        || (end.newer_changes && !start.newer_changes)
+    {
+        Some(true)
+    } else if start.newer_changes {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+fn get_vdisk_info(vdisk: &VirtDisk) -> VirtDiskInfo {
     let virtual_size = vdisk.get_virtual_size().unwrap();
     let parent_path = vdisk.get_parent_path().map_or_else(
         |e| match e.result() {
             ERROR_VHD_INVALID_TYPE => None,
-            _ => panic!(e),
+            _ => panic!("{}", e),
         },
-        |v| Some(v),
+        Some,
     );
     let virtual_storage_type = vdisk.get_virtual_storage_type().unwrap();
     let provider_sub_type = vdisk.get_provider_sub_type().unwrap();
+    let sector_size = vdisk.get_sector_size().unwrap();
+
+    VirtDiskInfo {
+        virtual_size,
+        parent_path,
+        virtual_storage_type,
+        provider_sub_type,
+        sector_size,
+    }
+}
 
-    Ok(Json(VirtDiskInfo {
-        virtual_size: virtual_size,
-        parent_path: parent_path,
-        virtual_storage_type: virtual_storage_type,
-        provider_sub_type: provider_sub_type,
-    }))
+#[get("/vdisk/<path>/info")]
+async fn get_disk_info(
+    path: String,
+    _format: StructuredFormat,
+    _key: AuthKeyGuard,
+) -> Result<Structured<VirtDiskInfo>, NotFound<String>> {
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true)?;
+        Ok(Structured(get_vdisk_info(&vdisk)))
+    })
+    .await
 }
 
-#[get("/vdisk/<path>/rct", format = "json")]
-fn get_rct_info(path: String, _key: AuthKeyGuard) -> Result<Json<RCTInfo>, NotFound<String>> {
-    let vdisk = open_vdisk(&path, true)?;
-    let rct_info = vdisk.get_rct_info().unwrap();
-    Ok(Json(rct_info))
+// Directories where new disks can be created, including their subdirectories
+struct DiskDirectories {
+    directories: Vec<PathBuf>,
+}
+
+impl DiskDirectories {
+    fn from_config(figment: &Figment) -> DiskDirectories {
+        let directories = figment
+            .extract_inner::<Vec<RelativePathBuf>>("disk_directories")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|d| match fs::canonicalize(d.relative()) {
+                Ok(directory) => Some(directory),
+                Err(e) => {
+                    warn!(
+                        "Ignoring the disk directory {}: {}",
+                        d.relative().display(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect();
+        DiskDirectories { directories }
+    }
+
+    // The path's directory is resolved, so that links can't point outside
+    // of the allowed directories
+    fn check(&self, path: &str) -> Result<(), status::Custom<String>> {
+        if self.directories.is_empty() {
+            return Err(status::Custom(
+                Status::ServiceUnavailable,
+                "No disk_directories configured".to_string(),
+            ));
+        }
+        let path = Path::new(path);
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        let directory = fs::canonicalize(directory).map_err(|e| {
+            status::Custom(
+                Status::NotFound,
+                format!("Unable to create {}: {}", path.display(), e),
+            )
+        })?;
+        if !self.directories.iter().any(|d| directory.starts_with(d)) {
+            return Err(status::Custom(
+                Status::Forbidden,
+                format!("{} is not in one of the disk_directories", path.display()),
+            ));
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(status::Custom(
+                Status::Conflict,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateVirtDiskRequest {
+    path: String,
+    #[serde(flatten)]
+    options: VirtDiskCreateOptions,
+}
+
+#[post("/vdisks", format = "application/json", data = "<request>")]
+async fn create_disk(
+    request: Json<CreateVirtDiskRequest>,
+    disk_directories: &State<DiskDirectories>,
+    _key: AuthKeyGuard,
+) -> Result<status::Created<Structured<VirtDiskInfo>>, status::Custom<String>> {
+    let request = request.into_inner();
+    let path = request.path.clone();
+    disk_directories.check(&path)?;
+    let info = blocking(move || {
+        let vdisk = VirtDisk::create(&request.path, &request.options).map_err(|e| {
+            let status = match e.result() {
+                ERROR_INVALID_PARAMETER => Status::BadRequest,
+                ERROR_FILE_EXISTS => Status::Conflict,
+                // Missing directory or parent disk
+                ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => Status::NotFound,
+                _ => panic!("{}", e),
+            };
+            status::Custom(status, format!("Unable to create {}: {}", request.path, e))
+        })?;
+        Ok(get_vdisk_info(&vdisk))
+    })
+    .await?;
+    let location = uri!(get_disk_info(path)).to_string();
+    Ok(status::Created::new(location).body(Structured(info)))
+}
+
+#[get("/vdisk/<path>/rct")]
+async fn get_rct_info(
+    path: String,
+    history: &State<RctHistory>,
+    _format: StructuredFormat,
+    _key: AuthKeyGuard,
+) -> Result<Structured<RCTInfo>, NotFound<String>> {
+    let history = history.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true)?;
+        let rct_info = vdisk.get_rct_info().unwrap();
+        if rct_info.enabled {
+            history.record(&path, &rct_info.most_recent_id, None);
+        }
+        Ok(Structured(rct_info))
+    })
+    .await
 }
 
 #[put("/vdisk/<path>/rct?<enabled>")]
-fn set_rct_info(path: String, enabled: bool, _key: AuthKeyGuard) -> Result<(), NotFound<String>> {
-    let mut vdisk = open_vdisk(&path, false)?;
-    vdisk.set_rct_info(enabled).unwrap();
-    Ok(())
+async fn set_rct_info(
+    path: String,
+    enabled: bool,
+    history: &State<RctHistory>,
+    _key: AuthKeyGuard,
+) -> Result<(), NotFound<String>> {
+    let history = history.inner().clone();
+    blocking(move || {
+        let mut vdisk = open_vdisk(&path, false)?;
+        vdisk.set_rct_info(enabled).unwrap();
+        if enabled {
+            let rct_info = vdisk.get_rct_info().unwrap();
+            history.record(&path, &rct_info.most_recent_id, None);
+        }
+        Ok(())
+    })
+    .await
 }
 
-#[get("/vdisk/<path>/rct/<rct_id>/changes", format = "json")]
-fn query_disk_changes(
+#[get("/vdisk/<path>/rct/<rct_id>/changes?<byte_offset>&<length>")]
+async fn query_disk_changes(
     path: String,
     rct_id: String,
+    byte_offset: Option<u64>,
+    length: Option<u64>,
+    metrics: &State<Metrics>,
+    _format: StructuredFormat,
     _key: AuthKeyGuard,
-) -> Result<Json<Vec<VirtualDiskChangeRange>>, NotFound<String>> {
-    let vdisk = open_vdisk(&path, true)?;
-    let disk_changes = vdisk.query_changes(&rct_id).map_err(|e| match e.result() {
-        ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION => {
-            NotFound(format!("RCT ID not found: {}", rct_id))
-        }
-        _ => panic!(e),
-    })?;
-    Ok(Json(disk_changes))
+) -> Result<Structured<Vec<VirtualDiskChangeRange>>, status::Custom<String>> {
+    let metrics = metrics.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let start = Instant::now();
+        let disk_changes =
+            changes::changes_iter(&vdisk, &rct_id, byte_offset, length).and_then(|iter| {
+                let mut disk_changes = Vec::new();
+                for batch in iter {
+                    disk_changes.extend(batch.map_err(changes::changes_error(&rct_id))?);
+                }
+                Ok(disk_changes)
+            });
+        metrics.query_changes_done(start.elapsed(), disk_changes.as_ref().ok().map(|c| c.len()));
+        Ok(Structured(disk_changes?))
+    })
+    .await
 }
 
 #[get("/vdisk/<path>/content?<ranges>")]
-fn get_disk_content(
+async fn get_disk_content(
     path: String,
     ranges: QueryStringRanges,
+    frames: FramesFormat,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
     _key: AuthKeyGuard,
-) -> Result<DiskContentResponder, NotFound<String>> {
-    get_disk_content_common(path, ranges.ranges)
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    get_disk_content_common(path, ranges.ranges, frames, read_config, metrics, permit).await
 }
 
 // Provide a POST alternative to GET due to the query string's length limits
-#[post(
-    "/vdisk/<path>/content",
-    format = "application/json",
-    data = "<ranges>"
-)]
-fn get_disk_content_post(
+#[post("/vdisk/<path>/content", data = "<ranges>")]
+async fn get_disk_content_post(
     path: String,
-    ranges: Json<Vec<VirtualDiskChangeRange>>,
+    ranges: Structured<Vec<VirtualDiskChangeRange>>,
+    frames: FramesFormat,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
     _key: AuthKeyGuard,
-) -> Result<DiskContentResponder, NotFound<String>> {
-    get_disk_content_common(path, ranges.to_vec())
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    get_disk_content_common(
+        path,
+        ranges.into_inner(),
+        frames,
+        read_config,
+        metrics,
+        permit,
+    )
+    .await
+}
+
+// Refuses ranges past the end of the disk, before reading any of them
+fn check_ranges(
+    ranges: &[VirtualDiskChangeRange],
+    disk_size: u64,
+) -> Result<(), status::Custom<String>> {
+    let exceeds = |r: &&VirtualDiskChangeRange| !matches!(r.offset.checked_add(r.length), Some(end) if end <= disk_size);
+    if let Some(range) = ranges.iter().find(exceeds) {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!(
+                "Range {}:{} exceeds the disk size: {}",
+                range.offset, range.length, disk_size
+            ),
+        ));
+    }
+    Ok(())
 }
 
-fn get_disk_content_common(
+async fn get_disk_content_common(
     path: String,
     ranges: Vec<VirtualDiskChangeRange>,
-) -> Result<DiskContentResponder, NotFound<String>> {
-    let vdisk = open_vdisk(&path, true)?;
-    vdisk.attach().unwrap();
-    let reader = VirtDiskReader::new(Box::new(vdisk), ranges);
-    Ok(DiskContentResponder {
-        reader: Box::new(reader),
+    frames: FramesFormat,
+    read_config: &ReadConfig,
+    metrics: &Metrics,
+    permit: StreamPermit,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let read_config = *read_config;
+    let metrics = metrics.clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let read_error = |e: String| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Unable to read {}: {}", path, e),
+            )
+        };
+        let disk_size = vdisk
+            .get_virtual_size()
+            .map_err(|e| read_error(e.to_string()))?;
+        check_ranges(&ranges, disk_size)?;
+        check_loaded(&vdisk, &path, &read_config)?;
+        // Compared with the state after the read, in the frames' trailer
+        let rct_info = vdisk
+            .get_rct_info()
+            .map_err(|e| status::Custom(Status::InternalServerError, e.to_string()))?;
+        if let Err(e) = vdisk.attach(true) {
+            metrics.disk_attach_failed();
+            return Err(read_error(e.to_string()));
+        }
+        metrics.disk_attached();
+        let reader = VirtDiskReader::open(
+            Box::new(vdisk),
+            ranges.clone(),
+            &read_config,
+            metrics,
+            permit,
+        )
+        .map_err(|e| read_error(e.to_string()))?;
+        let most_recent_id = rct_info.most_recent_id.clone();
+        let mut responder = if frames.0 {
+            let ranges_count = reader.ranges_count;
+            DiskContentResponder {
+                reader: Box::new(FramedContent::new(reader, ranges, rct_info)),
+                content_length: None,
+                content_type: "application/x-rct-frames",
+                headers: Vec::new(),
+                ranges: Some(ranges_count),
+            }
+        } else {
+            DiskContentResponder::new(reader)
+        };
+        responder
+            .headers
+            .push(("X-RCT-Most-Recent-Id", most_recent_id));
+        Ok(responder)
     })
+    .await
 }
 
-fn main() {
-    rocket::ignite()
-        .attach(AdHoc::on_attach("auth_key", |rocket| {
-            let auth_key = rocket
-                .config()
-                .get_string("auth_key")
-                .expect("auth_key is a required config option");
-            Ok(rocket.manage(AuthKey { auth_key: auth_key }))
+// Unauthenticated, to be scraped by Prometheus
+#[get("/metrics")]
+fn get_metrics(metrics: &State<Metrics>) -> content::RawText<String> {
+    content::RawText(metrics.render())
+}
+
+#[launch]
+fn rocket() -> _ {
+    service(rocket::Config::figment())
+}
+
+fn service(figment: Figment) -> Rocket<Build> {
+    let metrics = Metrics::new();
+
+    rocket::custom(figment)
+        .manage(metrics.clone())
+        .attach(MetricsFairing::new(metrics))
+        .attach(AdHoc::on_ignite("auth_key", |rocket| async {
+            let figment = rocket.figment();
+            let mut keys = HashMap::new();
+            if let Ok(auth_keys) = figment.extract_inner::<HashMap<String, String>>("auth_keys") {
+                for (key_id, auth_key) in auth_keys {
+                    keys.insert(auth_key, key_id);
+                }
+            }
+            if let Ok(auth_key) = figment.extract_inner::<String>("auth_key") {
+                keys.insert(auth_key, "default".to_string());
+            }
+            if keys.is_empty() {
+                panic!("auth_key or auth_keys is a required config option");
+            }
+            rocket.manage(AuthKeys { keys })
+        }))
+        .attach(AdHoc::on_ignite("audit_log", |rocket| async {
+            let figment = rocket.figment();
+            let path = match figment.extract_inner::<RelativePathBuf>("audit_log") {
+                Ok(path) => path.relative(),
+                Err(_) => return rocket,
+            };
+            let max_size = figment
+                .extract_inner::<u64>("audit_log_max_size")
+                .unwrap_or(100 * 1024 * 1024);
+            let max_files = figment
+                .extract_inner::<u32>("audit_log_max_files")
+                .unwrap_or(10);
+            let audit_log =
+                AuditLog::open(path, max_size, max_files).expect("Unable to open the audit log");
+            rocket.attach(AuditFairing::new(audit_log))
+        }))
+        .attach(AdHoc::on_ignite("disk_directories", |rocket| async {
+            let disk_directories = DiskDirectories::from_config(rocket.figment());
+            rocket.manage(disk_directories)
+        }))
+        .attach(AdHoc::on_ignite("ready_config", |rocket| async {
+            let ready_config = ReadyConfig::from_config(rocket.figment());
+            rocket.manage(ready_config)
+        }))
+        .attach(AdHoc::on_ignite("stream_limits", |rocket| async {
+            let stream_limits = StreamLimits::from_config(rocket.figment());
+            rocket.manage(stream_limits)
+        }))
+        .attach(AdHoc::on_ignite("rct_history", |rocket| async {
+            let history = RctHistory::from_config(rocket.figment());
+            rocket.manage(history)
+        }))
+        .attach(AdHoc::on_ignite("manifest_store", |rocket| async {
+            let store = ManifestStore::from_config(rocket.figment());
+            rocket.manage(store)
+        }))
+        .attach(AdHoc::on_ignite("jobs", |rocket| async {
+            let history = rocket.state::<RctHistory>().unwrap().clone();
+            let jobs = Jobs::from_config(rocket.figment(), history);
+            rocket.manage(jobs)
+        }))
+        .attach(AdHoc::on_ignite("read_config", |rocket| async {
+            let figment = rocket.figment();
+            // Reads must be aligned, round read_size down to READ_ALIGNMENT
+            let read_size = figment
+                .extract_inner::<usize>("read_size")
+                .unwrap_or(DEFAULT_READ_SIZE);
+            let read_size = std::cmp::max(read_size / READ_ALIGNMENT, 1) * READ_ALIGNMENT;
+            let read_ahead = figment
+                .extract_inner::<usize>("read_ahead")
+                .unwrap_or(DEFAULT_READ_AHEAD);
+            let refuse_loaded = figment
+                .extract_inner::<bool>("refuse_loaded_disks")
+                .unwrap_or(false);
+            rocket.manage(ReadConfig {
+                read_size,
+                read_ahead,
+                refuse_loaded,
+            })
         }))
+        .register("/", catchers![limits::too_many_requests])
         .mount(
             "/",
             routes![
                 get_disk_info,
+                create_disk,
                 get_rct_info,
                 set_rct_info,
+                history::get_rct_history,
                 query_disk_changes,
+                changes::query_disk_changes_ndjson,
+                changes::query_disk_changes_bitmap,
+                changes::get_changes_summary,
+                changes::get_changes_content,
+                changes::get_changes_content_post,
                 get_disk_content,
-                get_disk_content_post
+                get_disk_content_post,
+                export::get_disk_export,
+                export::get_disk_export_post,
+                manifest::create_manifest,
+                restore::put_disk_content,
+                restore::put_disk_content_framed,
+                jobs::create_job,
+                jobs::get_jobs,
+                jobs::get_job,
+                get_metrics,
+                health::get_health,
+                health::get_ready,
+                health::get_version
             ],
         )
-        .launch();
 }
@@ -0,0 +1,353 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Manifests of the SHA-256 of a disk's blocks, computed by reading the disk
+//! and stored for each disk and RCT ID, so that the blocks changed since
+//! then can be found even if the RCT ID is not valid anymore.
+
+use ring::digest;
+
+use rocket::figment::value::magic::RelativePathBuf;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use rctlib::*;
+
+use crate::changes::check_hash_block_size;
+use crate::jobs::disk_directory;
+use crate::limits::StreamPermit;
+use crate::metrics::Metrics;
+use crate::{
+    blocking, check_loaded, open_vdisk, AuthKeyGuard, DiskContentResponder, ReadConfig,
+    VirtDiskReader,
+};
+
+const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Directory storing the manifests, if configured.
+#[derive(Clone)]
+pub struct ManifestStore {
+    root: Option<PathBuf>,
+}
+
+impl ManifestStore {
+    pub fn from_config(figment: &Figment) -> ManifestStore {
+        let root = figment
+            .extract_inner::<RelativePathBuf>("manifest_store")
+            .ok()
+            .map(|r| r.relative());
+        ManifestStore { root }
+    }
+
+    // RCT IDs can contain characters not allowed in file names
+    fn path(&self, disk_path: &str, rct_id: &str) -> Option<PathBuf> {
+        let hash = hex_digest(&digest::digest(&digest::SHA256, rct_id.as_bytes()));
+        self.root.as_ref().map(|root| {
+            root.join(disk_directory(disk_path))
+                .join(format!("{}.json", &hash[..32]))
+        })
+    }
+
+    /// The manifest stored for the disk when its most recent RCT ID was
+    /// rct_id, if any.
+    pub fn load(&self, disk_path: &str, rct_id: &str) -> Option<BlockHashes> {
+        let path = self.path(disk_path, rct_id)?;
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Unable to open the manifest {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        // Corrupt manifests are ignored, as if missing
+        match serde_json::from_reader::<_, BlockHashes>(file) {
+            Ok(hashes) if check_hash_block_size(hashes.block_size).is_ok() => Some(hashes),
+            Ok(hashes) => {
+                warn!(
+                    "Invalid block size in the manifest {}: {}",
+                    path.display(),
+                    hashes.block_size
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Unable to read the manifest {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ManifestHeader<'a> {
+    block_size: u64,
+    disk_size: u64,
+    rct_id: &'a str,
+}
+
+// The manifest as a JSON object, deserializable as BlockHashes, generated
+// while the disk is read and optionally stored once complete. Blocks out of
+// the given ranges are hashed as zeros.
+struct ManifestStream {
+    reader: VirtDiskReader,
+    ranges: Vec<VirtualDiskChangeRange>,
+    range_index: usize,
+    block_size: u64,
+    disk_size: u64,
+    block: u64,
+    zero_hash: String,
+    buf: Vec<u8>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    // Temporary file and final path of the stored manifest
+    file: Option<(File, PathBuf, PathBuf)>,
+    done: bool,
+}
+
+impl ManifestStream {
+    fn new(
+        reader: VirtDiskReader,
+        ranges: Vec<VirtualDiskChangeRange>,
+        block_size: u64,
+        disk_size: u64,
+        rct_id: &str,
+        store_path: Option<PathBuf>,
+    ) -> io::Result<ManifestStream> {
+        let mut file = match store_path {
+            Some(path) => {
+                fs::create_dir_all(path.parent().unwrap())?;
+                let temp_path = path.with_extension("json.tmp");
+                Some((File::create(&temp_path)?, temp_path, path))
+            }
+            None => None,
+        };
+
+        // The hashes array is left open, to be filled as the blocks are read
+        let header = ManifestHeader {
+            block_size,
+            disk_size,
+            rct_id,
+        };
+        let buf = vec![0u8; block_size as usize];
+        let mut pending = serde_json::to_vec(&header)?;
+        pending.pop();
+        pending.extend_from_slice(b",\"hashes\":[");
+        if let Some((ref mut file, _, _)) = file {
+            file.write_all(&pending)?;
+        }
+
+        Ok(ManifestStream {
+            reader,
+            ranges,
+            range_index: 0,
+            block_size,
+            disk_size,
+            block: 0,
+            zero_hash: block_hash(&buf),
+            buf,
+            pending,
+            pending_pos: 0,
+            file,
+            done: false,
+        })
+    }
+
+    fn next_hash(&mut self) -> io::Result<String> {
+        let offset = self.block * self.block_size;
+        let length = std::cmp::min(self.block_size, self.disk_size - offset) as usize;
+        self.block += 1;
+        while self.range_index < self.ranges.len()
+            && self.ranges[self.range_index].offset + self.ranges[self.range_index].length <= offset
+        {
+            self.range_index += 1;
+        }
+        // Ranges are aligned to the blocks
+        match self.ranges.get(self.range_index) {
+            Some(range) if range.offset <= offset => {
+                self.reader.read_exact(&mut self.buf[..length])?;
+                Ok(block_hash(&self.buf[..length]))
+            }
+            _ if length == self.block_size as usize => Ok(self.zero_hash.clone()),
+            _ => {
+                self.buf[..length].iter_mut().for_each(|b| *b = 0);
+                Ok(block_hash(&self.buf[..length]))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some((file, temp_path, path)) = self.file.take() {
+            file.sync_all()?;
+            fs::rename(temp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+// Incomplete manifests are not stored
+impl Drop for ManifestStream {
+    fn drop(&mut self) {
+        if let Some((_, ref temp_path, _)) = self.file {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+impl Read for ManifestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_pos == self.pending.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.pending.clear();
+            self.pending_pos = 0;
+            if self.block * self.block_size < self.disk_size {
+                if self.block > 0 {
+                    self.pending.push(b',');
+                }
+                let hash = self.next_hash()?;
+                serde_json::to_writer(&mut self.pending, &hash)?;
+            } else {
+                self.pending.extend_from_slice(b"]}\n");
+                self.done = true;
+            }
+            if let Some((ref mut file, _, _)) = self.file {
+                file.write_all(&self.pending)?;
+            }
+            if self.done {
+                self.finish()?;
+            }
+        }
+
+        let length = std::cmp::min(buf.len(), self.pending.len() - self.pending_pos);
+        buf[..length].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + length]);
+        self.pending_pos += length;
+        Ok(length)
+    }
+}
+
+/// Reads the whole disk, or only its allocated ranges, and streams the
+/// SHA-256 of each block as a JSON manifest, accepted by the hash based
+/// delta. If a manifest store is configured, the manifest is also stored
+/// for the disk's most recent RCT ID, used by later deltas from that ID.
+#[post("/vdisk/<path>/manifest?<block_size>&<allocated>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_manifest(
+    path: String,
+    block_size: Option<u64>,
+    allocated: Option<bool>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    store: &State<ManifestStore>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> Result<DiskContentResponder, status::Custom<String>> {
+    let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    check_hash_block_size(block_size)?;
+
+    let read_config = *read_config.inner();
+    let metrics = metrics.inner().clone();
+    let store = store.inner().clone();
+    blocking(move || {
+        let vdisk = open_vdisk(&path, true).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let internal_error = |e: String| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Unable to create the manifest of {}: {}", path, e),
+            )
+        };
+        let disk_size = vdisk
+            .get_virtual_size()
+            .map_err(|e| internal_error(e.to_string()))?;
+        // The manifest describes the disk as of this RCT ID
+        let rct_info = vdisk
+            .get_rct_info()
+            .map_err(|e| internal_error(e.to_string()))?;
+
+        let ranges = if allocated.unwrap_or(false) {
+            let start = Instant::now();
+            let ranges = vdisk.query_changes(ALLOCATED_RANGES_ID);
+            metrics.query_changes_done(start.elapsed(), ranges.as_ref().ok().map(|r| r.len()));
+            let ranges = ranges.map_err(|e| match e.result() {
+                ERROR_VHD_MISSING_CHANGE_TRACKING_INFORMATION => status::Custom(
+                    Status::BadRequest,
+                    format!("The allocated ranges of {} are not available", path),
+                ),
+                _ => internal_error(e.to_string()),
+            })?;
+            align_ranges(&ranges, block_size, disk_size)
+        } else {
+            vec![VirtualDiskChangeRange {
+                offset: 0,
+                length: disk_size,
+            }]
+        };
+
+        // Disks without RCT report an empty ID, nothing to store it for
+        let store_path = if rct_info.enabled && !rct_info.most_recent_id.is_empty() {
+            store.path(&path, &rct_info.most_recent_id)
+        } else {
+            None
+        };
+
+        check_loaded(&vdisk, &path, &read_config)?;
+        if let Err(e) = vdisk.attach(true) {
+            metrics.disk_attach_failed();
+            return Err(internal_error(e.to_string()));
+        }
+        metrics.disk_attached();
+        let reader = VirtDiskReader::open(
+            Box::new(vdisk),
+            ranges.clone(),
+            &read_config,
+            metrics,
+            permit,
+        )
+        .map_err(|e| internal_error(e.to_string()))?;
+        let ranges_count = reader.ranges_count;
+        let store_display = store_path
+            .as_ref()
+            .map_or(String::new(), |p| p.display().to_string());
+        let stream = ManifestStream::new(
+            reader,
+            ranges,
+            block_size,
+            disk_size,
+            &rct_info.most_recent_id,
+            store_path,
+        )
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Unable to store the manifest in {}: {}", store_display, e),
+            )
+        })?;
+        Ok(DiskContentResponder {
+            reader: Box::new(stream),
+            content_length: None,
+            content_type: "application/json",
+            headers: vec![("X-RCT-Most-Recent-Id", rct_info.most_recent_id)],
+            ranges: Some(ranges_count),
+        })
+    })
+    .await
+}
@@ -0,0 +1,404 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rctlib::ReadStats;
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+const THROUGHPUT_BUCKETS: &[f64] = &[
+    1048576.0,
+    10485760.0,
+    52428800.0,
+    104857600.0,
+    262144000.0,
+    524288000.0,
+    1073741824.0,
+];
+const RANGE_COUNT_BUCKETS: &[f64] = &[0.0, 1.0, 10.0, 100.0, 1000.0, 10000.0, 100000.0];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if value <= *bucket {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bucket, count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        )
+        .unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+impl RequestLabels {
+    fn format(&self) -> String {
+        format!(
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            self.method,
+            escape_label(&self.route),
+            self.status
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct MetricsData {
+    requests: Mutex<BTreeMap<RequestLabels, Histogram>>,
+    bytes_streamed: AtomicU64,
+    bytes_restored: AtomicU64,
+    active_streams: AtomicU64,
+    attaches: AtomicU64,
+    attach_failures: AtomicU64,
+    detaches: AtomicU64,
+    auth_failures: AtomicU64,
+    query_changes_duration: Mutex<Histogram>,
+    query_changes_ranges: Mutex<Histogram>,
+    query_changes_failures: AtomicU64,
+    disk_read_bytes: AtomicU64,
+    disk_read_nanos: AtomicU64,
+    stream_throughput: Mutex<Histogram>,
+}
+
+/// Service metrics, exposed in the Prometheus text format.
+#[derive(Clone)]
+pub struct Metrics {
+    data: Arc<MetricsData>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            data: Arc::new(MetricsData {
+                requests: Mutex::new(BTreeMap::new()),
+                bytes_streamed: AtomicU64::new(0),
+                bytes_restored: AtomicU64::new(0),
+                active_streams: AtomicU64::new(0),
+                attaches: AtomicU64::new(0),
+                attach_failures: AtomicU64::new(0),
+                detaches: AtomicU64::new(0),
+                auth_failures: AtomicU64::new(0),
+                query_changes_duration: Mutex::new(Histogram::new(LATENCY_BUCKETS)),
+                query_changes_ranges: Mutex::new(Histogram::new(RANGE_COUNT_BUCKETS)),
+                query_changes_failures: AtomicU64::new(0),
+                disk_read_bytes: AtomicU64::new(0),
+                disk_read_nanos: AtomicU64::new(0),
+                stream_throughput: Mutex::new(Histogram::new(THROUGHPUT_BUCKETS)),
+            }),
+        }
+    }
+
+    fn observe_request(&self, labels: RequestLabels, duration: Duration) {
+        self.data
+            .requests
+            .lock()
+            .unwrap()
+            .entry(labels)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn add_bytes_streamed(&self, bytes: u64) {
+        self.data.bytes_streamed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_restored(&self, bytes: u64) {
+        self.data.bytes_restored.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn stream_started(&self) {
+        self.data.active_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_finished(&self) {
+        self.data.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn disk_attached(&self) {
+        self.data.attaches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disk_attach_failed(&self) {
+        self.data.attach_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disk_detached(&self) {
+        self.data.detaches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.data.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn query_changes_done(&self, duration: Duration, ranges: Option<usize>) {
+        let data = &self.data;
+        data.query_changes_duration
+            .lock()
+            .unwrap()
+            .observe(duration.as_secs_f64());
+        match ranges {
+            Some(ranges) => data
+                .query_changes_ranges
+                .lock()
+                .unwrap()
+                .observe(ranges as f64),
+            None => {
+                data.query_changes_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn observe_stream(&self, read_stats: &ReadStats, duration: Duration) {
+        let data = &self.data;
+        data.disk_read_bytes
+            .fetch_add(read_stats.bytes(), Ordering::Relaxed);
+        data.disk_read_nanos
+            .fetch_add(read_stats.read_time().as_nanos() as u64, Ordering::Relaxed);
+        let secs = duration.as_secs_f64();
+        if secs > 0.0 {
+            data.stream_throughput
+                .lock()
+                .unwrap()
+                .observe(read_stats.bytes() as f64 / secs);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let data = &self.data;
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "rct_bytes_streamed_total",
+                "counter",
+                "Disk content bytes streamed to clients.",
+                data.bytes_streamed.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_bytes_restored_total",
+                "counter",
+                "Bytes written back to virtual disks by restores.",
+                data.bytes_restored.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_active_streams",
+                "gauge",
+                "Disk content streams currently in progress.",
+                data.active_streams.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_disk_attaches_total",
+                "counter",
+                "Virtual disks attached.",
+                data.attaches.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_disk_attach_failures_total",
+                "counter",
+                "Virtual disk attach failures.",
+                data.attach_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_disk_detaches_total",
+                "counter",
+                "Virtual disks detached.",
+                data.detaches.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_auth_failures_total",
+                "counter",
+                "Requests rejected due to a missing or invalid auth_key.",
+                data.auth_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_query_changes_failures_total",
+                "counter",
+                "RCT change queries that failed.",
+                data.query_changes_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "rct_disk_read_bytes_total",
+                "counter",
+                "Bytes read from the attached disks.",
+                data.disk_read_bytes.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, kind, help, value) in counters.iter() {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP rct_disk_read_seconds_total Time spent reading from the attached disks."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE rct_disk_read_seconds_total counter").unwrap();
+        writeln!(
+            out,
+            "rct_disk_read_seconds_total {}",
+            data.disk_read_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP rct_stream_throughput_bytes_per_second Throughput of completed content streams."
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE rct_stream_throughput_bytes_per_second histogram"
+        )
+        .unwrap();
+        data.stream_throughput.lock().unwrap().write(
+            &mut out,
+            "rct_stream_throughput_bytes_per_second",
+            "",
+        );
+
+        writeln!(
+            out,
+            "# HELP rct_query_changes_duration_seconds Duration of RCT change queries."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE rct_query_changes_duration_seconds histogram").unwrap();
+        data.query_changes_duration.lock().unwrap().write(
+            &mut out,
+            "rct_query_changes_duration_seconds",
+            "",
+        );
+
+        writeln!(
+            out,
+            "# HELP rct_query_changes_ranges Ranges returned by RCT change queries."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE rct_query_changes_ranges histogram").unwrap();
+        data.query_changes_ranges
+            .lock()
+            .unwrap()
+            .write(&mut out, "rct_query_changes_ranges", "");
+
+        writeln!(
+            out,
+            "# HELP rct_http_request_duration_seconds HTTP request latency until the response headers are sent."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE rct_http_request_duration_seconds histogram").unwrap();
+        for (labels, histogram) in data.requests.lock().unwrap().iter() {
+            histogram.write(
+                &mut out,
+                "rct_http_request_duration_seconds",
+                &labels.format(),
+            );
+        }
+
+        out
+    }
+}
+
+struct RequestStart(Instant);
+
+/// Records the count and latency of every request per route and status.
+pub struct MetricsFairing {
+    metrics: Metrics,
+}
+
+impl MetricsFairing {
+    pub fn new(metrics: Metrics) -> MetricsFairing {
+        MetricsFairing { metrics }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let labels = RequestLabels {
+            method: request.method().to_string(),
+            route: request
+                .route()
+                .map_or_else(|| "unmatched".to_string(), |r| r.uri.to_string()),
+            status: response.status().code,
+        };
+        self.metrics.observe_request(labels, start.elapsed());
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+use rocket::data::{ByteUnit, Data};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task;
+use rocket::State;
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io;
+
+use rctlib::*;
+
+use crate::limits::StreamPermit;
+use crate::metrics::Metrics;
+use crate::{blocking, open_vdisk, AuthKeyGuard, QueryStringRanges, ReadConfig, CHUNK_SIZE};
+
+// Offset and length, big endian
+pub const FRAME_HEADER_SIZE: usize = 16;
+// Offset of the empty frame marking the end of the frames, anything after it
+// is ignored
+pub const FRAMES_END_OFFSET: u64 = u64::MAX;
+
+// Chunks of the request body queued for the blocking disk writer
+const WRITE_QUEUE_SIZE: usize = 4;
+
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    pub ranges: usize,
+    pub bytes_written: u64,
+    /// SHA-256 of the data, in the order it was received
+    pub sha256: String,
+}
+
+type RestoreResult = Result<Json<RestoreReport>, status::Custom<String>>;
+
+// Maps the request body to disk offsets
+enum ContentLayout {
+    // Data of the given ranges, concatenated
+    Raw {
+        ranges: VecDeque<VirtualDiskChangeRange>,
+        count: usize,
+    },
+    // Frames made of a header followed by the data
+    Framed {
+        header: Vec<u8>,
+        frame: Option<VirtualDiskChangeRange>,
+        count: usize,
+        ended: bool,
+    },
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Writes as much data as possible to the given range, advancing it
+fn write_to_range<'a>(
+    writer: &mut RangeWriter,
+    range: &mut VirtualDiskChangeRange,
+    data: &'a [u8],
+) -> io::Result<&'a [u8]> {
+    let length = std::cmp::min(range.length, data.len() as u64) as usize;
+    writer.write_range(range.offset, &data[..length])?;
+    range.offset += length as u64;
+    range.length -= length as u64;
+    Ok(&data[length..])
+}
+
+impl ContentLayout {
+    fn raw(ranges: Vec<VirtualDiskChangeRange>) -> ContentLayout {
+        ContentLayout::Raw {
+            count: ranges.len(),
+            ranges: ranges.into_iter().filter(|r| r.length > 0).collect(),
+        }
+    }
+
+    fn framed() -> ContentLayout {
+        ContentLayout::Framed {
+            header: Vec::with_capacity(FRAME_HEADER_SIZE),
+            frame: None,
+            count: 0,
+            ended: false,
+        }
+    }
+
+    fn write(&mut self, writer: &mut RangeWriter, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self {
+                ContentLayout::Raw { ranges, .. } => {
+                    let range = match ranges.front_mut() {
+                        Some(range) => range,
+                        None => {
+                            return Err(invalid_data(
+                                "The content exceeds the total length of the ranges".to_string(),
+                            ))
+                        }
+                    };
+                    data = write_to_range(writer, range, data)?;
+                    if range.length == 0 {
+                        ranges.pop_front();
+                    }
+                }
+                ContentLayout::Framed { ended: true, .. } => break,
+                ContentLayout::Framed {
+                    header,
+                    frame,
+                    count,
+                    ended,
+                } => match frame {
+                    Some(range) => {
+                        data = write_to_range(writer, range, data)?;
+                        if range.length == 0 {
+                            *frame = None;
+                        }
+                    }
+                    None => {
+                        let length = std::cmp::min(FRAME_HEADER_SIZE - header.len(), data.len());
+                        header.extend_from_slice(&data[..length]);
+                        data = &data[length..];
+                        if header.len() == FRAME_HEADER_SIZE {
+                            let mut value = [0u8; 8];
+                            value.copy_from_slice(&header[..8]);
+                            let offset = u64::from_be_bytes(value);
+                            value.copy_from_slice(&header[8..]);
+                            let length = u64::from_be_bytes(value);
+                            header.clear();
+                            if offset == FRAMES_END_OFFSET && length == 0 {
+                                *ended = true;
+                                continue;
+                            }
+                            // Checked before writing any of the frame's data
+                            writer.check_range(offset, length)?;
+                            *count += 1;
+                            if length > 0 {
+                                *frame = Some(VirtualDiskChangeRange { offset, length });
+                            }
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Raw ranges are all checked before writing, frames as they are received
+    fn check_ranges(&self, disk_size: u64, sector_size: u64) -> io::Result<()> {
+        if let ContentLayout::Raw { ranges, .. } = self {
+            for range in ranges.iter() {
+                check_write_range(range.offset, range.length, disk_size, sector_size)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the number of ranges written, failing if the content is incomplete
+    fn finish(&self) -> io::Result<usize> {
+        match self {
+            ContentLayout::Raw { ranges, count } => match ranges.front() {
+                Some(range) => Err(invalid_data(format!(
+                    "Missing content for range {}:{} and {} more",
+                    range.offset,
+                    range.length,
+                    ranges.len() - 1
+                ))),
+                None => Ok(*count),
+            },
+            ContentLayout::Framed {
+                header,
+                frame,
+                count,
+                ..
+            } => {
+                if !header.is_empty() || frame.is_some() {
+                    return Err(invalid_data("Truncated frame".to_string()));
+                }
+                Ok(*count)
+            }
+        }
+    }
+}
+
+// Receives the request body chunks and writes them to the disk. An empty
+// chunk marks the end of the body.
+fn write_content(
+    mut receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    mut layout: ContentLayout,
+    mut writer: RangeWriter,
+    metrics: Metrics,
+    permit: StreamPermit,
+) -> io::Result<RestoreReport> {
+    loop {
+        match receiver.blocking_recv() {
+            Some(Ok(ref chunk)) if chunk.is_empty() => break,
+            Some(Ok(chunk)) => {
+                layout.write(&mut writer, &chunk)?;
+                metrics.add_bytes_restored(chunk.len() as u64);
+                permit.throttle(chunk.len() as u64);
+            }
+            Some(Err(e)) => return Err(e),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The request was aborted",
+                ))
+            }
+        }
+    }
+
+    let ranges = layout.finish()?;
+    let summary = writer.finish()?;
+    Ok(RestoreReport {
+        ranges,
+        bytes_written: summary.bytes_written,
+        sha256: summary.sha256,
+    })
+}
+
+async fn put_disk_content_common(
+    path: String,
+    layout: ContentLayout,
+    data: Data<'_>,
+    limit: ByteUnit,
+    read_config: &ReadConfig,
+    metrics: &Metrics,
+    permit: StreamPermit,
+) -> RestoreResult {
+    let write_size = read_config.read_size;
+    let metrics = metrics.clone();
+    let writer_metrics = metrics.clone();
+    let (vdisk, writer, layout) = blocking(move || {
+        let vdisk = open_vdisk(&path, false).map_err(|e| status::Custom(Status::NotFound, e.0))?;
+        let internal_error = |e: String| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Unable to write {}: {}", path, e),
+            )
+        };
+        let disk_size = vdisk
+            .get_virtual_size()
+            .map_err(|e| internal_error(e.to_string()))?;
+        let sector_size = vdisk
+            .get_sector_size()
+            .map_err(|e| internal_error(e.to_string()))?;
+        layout
+            .check_ranges(disk_size, sector_size)
+            .map_err(|e| status::Custom(Status::BadRequest, e.to_string()))?;
+
+        if let Err(e) = vdisk.attach(false) {
+            metrics.disk_attach_failed();
+            return Err(internal_error(e.to_string()));
+        }
+        metrics.disk_attached();
+        let file = vdisk
+            .get_physical_disk_path()
+            .map_err(|e| e.to_string())
+            .and_then(|disk_path| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(disk_path)
+                    .map_err(|e| e.to_string())
+            });
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                drop(vdisk);
+                metrics.disk_detached();
+                return Err(internal_error(e));
+            }
+        };
+        let writer = RangeWriter::new(file, disk_size, sector_size, write_size);
+        Ok((vdisk, writer, layout))
+    })
+    .await?;
+
+    let (sender, receiver) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let write_task = task::spawn_blocking(move || {
+        let result = write_content(receiver, layout, writer, writer_metrics.clone(), permit);
+        // The virtual disk is detached when its handle is closed
+        drop(vdisk);
+        writer_metrics.disk_detached();
+        result
+    });
+
+    // Stops at the first write error, as the write task drops the receiver
+    let mut stream = data.open(limit);
+    loop {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let (chunk, done) = match stream.read(&mut buf).await {
+            Ok(read) => {
+                buf.truncate(read);
+                (Ok(buf), read == 0)
+            }
+            Err(e) => (Err(e), true),
+        };
+        if sender.send(chunk).await.is_err() || done {
+            break;
+        }
+    }
+    drop(sender);
+
+    let result = write_task
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    match result {
+        Ok(report) => Ok(Json(report)),
+        // Bad ranges or content, or the client went away
+        Err(ref e)
+            if e.kind() == io::ErrorKind::InvalidInput
+                || e.kind() == io::ErrorKind::InvalidData
+                || e.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            Err(status::Custom(Status::BadRequest, e.to_string()))
+        }
+        Err(e) => Err(status::Custom(
+            Status::InternalServerError,
+            format!("Unable to restore the content: {}", e),
+        )),
+    }
+}
+
+/// Writes the concatenated data of the given ranges back into the disk.
+#[put(
+    "/vdisk/<path>/content?<ranges>",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+pub async fn put_disk_content(
+    path: String,
+    ranges: QueryStringRanges,
+    data: Data<'_>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> RestoreResult {
+    // One more byte than needed, to detect content exceeding the ranges
+    let length = ranges
+        .ranges
+        .iter()
+        .try_fold(1u64, |total, r| total.checked_add(r.length))
+        .ok_or_else(|| {
+            status::Custom(
+                Status::BadRequest,
+                "The total length of the ranges is too large".to_string(),
+            )
+        })?;
+    let limit = ByteUnit::from(length);
+    let layout = ContentLayout::raw(ranges.ranges);
+    put_disk_content_common(path, layout, data, limit, read_config, metrics, permit).await
+}
+
+/// Writes framed data back into the disk: each frame is a 16 bytes header
+/// with the big endian offset and length, followed by the data.
+#[put(
+    "/vdisk/<path>/content",
+    format = "application/x-rct-frames",
+    data = "<data>"
+)]
+pub async fn put_disk_content_framed(
+    path: String,
+    data: Data<'_>,
+    read_config: &State<ReadConfig>,
+    metrics: &State<Metrics>,
+    permit: StreamPermit,
+    _key: AuthKeyGuard,
+) -> RestoreResult {
+    let layout = ContentLayout::framed();
+    put_disk_content_common(
+        path,
+        layout,
+        data,
+        ByteUnit::max_value(),
+        read_config,
+        metrics,
+        permit,
+    )
+    .await
+}
@@ -0,0 +1,762 @@
+// Copyright 2019 Cloudbase Solutions Srl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License. You may obtain
+// a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations
+// under the License.
+
+//! Tests of the routes through a local client, against raw and VHDX images
+//! in a temporary directory. The images' change lists are written directly.
+
+use super::service;
+
+use rctlib::VhdxReader;
+use rocket::figment::Figment;
+use rocket::http::{Accept, ContentType, Header, MediaType, Status};
+use rocket::local::blocking::{Client, LocalResponse};
+use serde_json::{json, Value};
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const MIB: usize = 1024 * 1024;
+
+// Directory removed with its content when dropped
+struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let path =
+            std::env::temp_dir().join(format!("rct-service-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir {
+            path: fs::canonicalize(path).unwrap(),
+        }
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn client(dir: &TestDir) -> Client {
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("auth_key", "secret"))
+        .merge(("disk_directories", vec![dir.path.to_str().unwrap()]));
+    Client::tracked(service(figment)).unwrap()
+}
+
+fn auth() -> Header<'static> {
+    Header::new("auth_key", "secret")
+}
+
+fn frames_type() -> MediaType {
+    MediaType::new("application", "x-rct-frames")
+}
+
+fn encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for b in segment.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// The disk path is a single segment, its separators must be encoded
+fn disk_uri(path: &Path, route: &str) -> String {
+    format!("/vdisk/{}{}", encode(path.to_str().unwrap()), route)
+}
+
+fn pattern(seed: u8, length: usize) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i % 251) as u8 ^ seed.wrapping_mul(31))
+        .collect()
+}
+
+fn write_change_list(path: &Path, change_list: &Value) {
+    let path = format!("{}.rct.json", path.display());
+    fs::write(path, serde_json::to_vec(change_list).unwrap()).unwrap();
+}
+
+fn json_body(response: LocalResponse) -> Value {
+    serde_json::from_slice(&response.into_bytes().unwrap()).unwrap()
+}
+
+fn frame(offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&offset.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+// Splits frames into their offsets and data, followed by the parsed trailer
+fn parse_frames(body: &[u8]) -> (Vec<(u64, Vec<u8>)>, Value) {
+    let mut frames = Vec::new();
+    let mut position = 0;
+    loop {
+        let mut header = [0u8; 8];
+        header.copy_from_slice(&body[position..position + 8]);
+        let offset = u64::from_be_bytes(header);
+        header.copy_from_slice(&body[position + 8..position + 16]);
+        let length = u64::from_be_bytes(header) as usize;
+        position += 16;
+        if offset == u64::MAX && length == 0 {
+            return (frames, serde_json::from_slice(&body[position..]).unwrap());
+        }
+        frames.push((offset, body[position..position + length].to_vec()));
+        position += length;
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn raw_content_ranges() {
+    let dir = TestDir::new("content");
+    let path = dir.join("disk.raw");
+    let data = pattern(1, 4 * MIB);
+    fs::write(&path, &data).unwrap();
+    let client = client(&dir);
+
+    let response = client
+        .get(disk_uri(
+            &path,
+            "/content?ranges=0:4096,1000:10,1048576:65536",
+        ))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let mut expected = data[0..4096].to_vec();
+    expected.extend_from_slice(&data[1000..1010]);
+    expected.extend_from_slice(&data[MIB..MIB + 65536]);
+    assert_eq!(response.into_bytes().unwrap(), expected);
+
+    let response = client
+        .post(disk_uri(&path, "/content"))
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(
+            json!([
+                {"offset": 0, "length": 4096},
+                {"offset": 1000, "length": 10},
+                {"offset": MIB, "length": 65536}
+            ])
+            .to_string(),
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().unwrap(), expected);
+
+    let response = client
+        .get(disk_uri(&path, "/content?ranges=512:1024,3145728:1048576"))
+        .header(auth())
+        .header(Accept::from(frames_type()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType(frames_type())));
+    let (frames, trailer) = parse_frames(&response.into_bytes().unwrap());
+    assert_eq!(
+        frames,
+        vec![
+            (512, data[512..1536].to_vec()),
+            (3 * MIB as u64, data[3 * MIB..].to_vec())
+        ]
+    );
+    assert_eq!(trailer["ranges"], 2);
+    assert_eq!(trailer["bytes"], 1024 + MIB);
+
+    for ranges in ["0:512,4194304:512", "512:18446744073709551615"].iter() {
+        let response = client
+            .get(disk_uri(&path, &format!("/content?ranges={}", ranges)))
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    let response = client
+        .get(disk_uri(&dir.join("missing.raw"), "/content?ranges=0:512"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .get(disk_uri(&path, "/content?ranges=0:512"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn raw_change_lists() {
+    let dir = TestDir::new("changes");
+    let path = dir.join("disk.raw");
+    let data = pattern(2, 4 * MIB);
+    fs::write(&path, &data).unwrap();
+    write_change_list(
+        &path,
+        &json!({
+            "enabled": true,
+            "snapshots": [
+                {"id": "snap-1"},
+                {"id": "snap-2", "changes": [{"offset": 65536, "length": 4096}]},
+                {"id": "snap-3", "changes": [
+                    {"offset": 0, "length": 512},
+                    {"offset": 69632, "length": 4096}
+                ]}
+            ]
+        }),
+    );
+    let client = client(&dir);
+
+    let response = client
+        .get(disk_uri(&path, "/rct"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let rct_info = json_body(response);
+    assert_eq!(rct_info["enabled"], true);
+    assert_eq!(rct_info["most_recent_id"], "snap-3");
+
+    let changes = |rct_id: &str| {
+        let response = client
+            .get(disk_uri(&path, &format!("/rct/{}/changes", encode(rct_id))))
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        json_body(response)
+    };
+    // Adjacent ranges of different snapshots are merged
+    assert_eq!(
+        changes("snap-1"),
+        json!([{"offset": 0, "length": 512}, {"offset": 65536, "length": 8192}])
+    );
+    assert_eq!(
+        changes("snap-2"),
+        json!([{"offset": 0, "length": 512}, {"offset": 69632, "length": 4096}])
+    );
+    assert_eq!(changes("snap-3"), json!([]));
+
+    // The most preferred type picks the route, lower weights don't fall back
+    // to JSON
+    for (accept, content_type) in [
+        ("application/x-ndjson, */*;q=0.1", "application/x-ndjson"),
+        ("application/octet-stream, */*", "application/octet-stream"),
+        (
+            "application/msgpack;q=0.5, application/json",
+            "application/json",
+        ),
+    ]
+    .iter()
+    {
+        let response = client
+            .get(disk_uri(&path, "/rct/snap-1/changes"))
+            .header(auth())
+            .header(Header::new("Accept", *accept))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Content-Type"),
+            Some(*content_type)
+        );
+    }
+
+    let response = client
+        .get(disk_uri(&path, "/rct/snap-0/changes"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .get(disk_uri(&path, "/rct/snap-1/summary?regions=4"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let summary = json_body(response);
+    assert_eq!(summary["ranges"], 2);
+    assert_eq!(summary["bytes"], 512 + 8192);
+    assert_eq!(summary["regions"], json!([512 + 8192, 0, 0, 0]));
+
+    let response = client
+        .get(disk_uri(&path, "/rct/snap-1/content"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.headers().get_one("X-RCT-Most-Recent-Id"),
+        Some("snap-3")
+    );
+    assert_eq!(response.headers().get_one("X-RCT-Delta"), Some("rct"));
+    let (frames, trailer) = parse_frames(&response.into_bytes().unwrap());
+    assert_eq!(
+        frames,
+        vec![
+            (0, data[0..512].to_vec()),
+            (65536, data[65536..65536 + 8192].to_vec())
+        ]
+    );
+    assert_eq!(trailer["most_recent_id_end"], "snap-3");
+    assert_eq!(trailer["disk_changed"], false);
+}
+
+#[test]
+fn raw_restore() {
+    let dir = TestDir::new("restore");
+    let path = dir.join("disk.raw");
+    let client = client(&dir);
+
+    let request = json!({
+        "path": path.to_str().unwrap(),
+        "format": "raw",
+        "disk_type": "dynamic",
+        "virtual_size": 4 * MIB
+    });
+    let response = client
+        .post("/vdisks")
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(request.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(json_body(response)["virtual_size"], 4 * MIB);
+
+    let response = client
+        .post("/vdisks")
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(request.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let outside = TestDir::new("restore-outside");
+    let mut request = request.clone();
+    request["path"] = json!(outside.join("disk.raw").to_str().unwrap());
+    let response = client
+        .post("/vdisks")
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(request.to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let data = pattern(3, 12288);
+    let response = client
+        .put(disk_uri(&path, "/content?ranges=0:4096,1048576:8192"))
+        .header(auth())
+        .header(ContentType::Binary)
+        .body(&data)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let report = json_body(response);
+    assert_eq!(report["ranges"], 2);
+    assert_eq!(report["bytes_written"], 12288);
+    assert_eq!(report["sha256"], sha256_hex(&data));
+
+    // More data than the ranges hold is refused
+    let response = client
+        .put(disk_uri(&path, "/content?ranges=0:512"))
+        .header(auth())
+        .header(ContentType::Binary)
+        .body(&data[..1024])
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let framed = pattern(4, 65536);
+    let mut body = frame(2 * MIB as u64, &framed[..4096]);
+    body.extend(frame(3 * MIB as u64, &framed[4096..]));
+    body.extend(frame(u64::MAX, &[]));
+    let response = client
+        .put(disk_uri(&path, "/content"))
+        .header(auth())
+        .header(ContentType(frames_type()))
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let report = json_body(response);
+    assert_eq!(report["ranges"], 2);
+    assert_eq!(report["bytes_written"], 65536);
+    assert_eq!(report["sha256"], sha256_hex(&framed));
+
+    // Bad ranges are refused before anything is written
+    for ranges in [
+        "3145728:4096,1048577:4096",
+        "3145728:4096,4190208:8192",
+        "3145728:4096,512:18446744073709551615",
+    ]
+    .iter()
+    {
+        let response = client
+            .put(disk_uri(&path, &format!("/content?ranges={}", ranges)))
+            .header(auth())
+            .header(ContentType::Binary)
+            .body(&data[..8192])
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    // A frame past the end of the disk is refused
+    let mut body = frame(4 * MIB as u64, &data[..4096]);
+    body.extend(frame(u64::MAX, &[]));
+    let response = client
+        .put(disk_uri(&path, "/content"))
+        .header(auth())
+        .header(ContentType(frames_type()))
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let mut expected = vec![0u8; 4 * MIB];
+    expected[0..4096].copy_from_slice(&data[..4096]);
+    expected[MIB..MIB + 8192].copy_from_slice(&data[4096..]);
+    expected[2 * MIB..2 * MIB + 4096].copy_from_slice(&framed[..4096]);
+    expected[3 * MIB..3 * MIB + 61440].copy_from_slice(&framed[4096..]);
+    assert!(fs::read(&path).unwrap() == expected);
+}
+
+#[test]
+fn raw_export() {
+    let dir = TestDir::new("export");
+    let path = dir.join("disk.raw");
+    // Data in the first and third MiB, zeros elsewhere
+    let mut data = vec![0u8; 4 * MIB];
+    data[..MIB].copy_from_slice(&pattern(5, MIB));
+    data[2 * MIB + 4096..2 * MIB + 8192].copy_from_slice(&pattern(6, 4096));
+    fs::write(&path, &data).unwrap();
+    let client = client(&dir);
+
+    let export = |format: &str| {
+        let response = client
+            .get(disk_uri(&path, &format!("/export?format={}", format)))
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let content_length = response
+            .headers()
+            .get_one("Content-Length")
+            .map(|l| l.parse::<usize>().unwrap());
+        let body = response.into_bytes().unwrap();
+        assert_eq!(content_length, Some(body.len()));
+        body
+    };
+
+    assert!(export("raw") == data);
+
+    let image = export("qcow2");
+    assert_eq!(&image[..4], b"QFI\xfb");
+    // Blocks of zeros are omitted
+    assert!(image.len() < data.len());
+
+    let image_path = dir.join("export.vhdx");
+    fs::write(&image_path, export("vhdx")).unwrap();
+    let mut reader = VhdxReader::open(&image_path).unwrap();
+    assert_eq!(reader.virtual_size(), data.len() as u64);
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).unwrap();
+    assert!(content == data);
+
+    for ranges in ["4194304:512", "512:18446744073709551615"].iter() {
+        let response = client
+            .get(disk_uri(
+                &path,
+                &format!("/export?format=qcow2&ranges={}", ranges),
+            ))
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
+
+#[test]
+fn vhdx_images() {
+    let dir = TestDir::new("vhdx");
+    let path = dir.join("disk.vhdx");
+    let client = client(&dir);
+
+    let response = client
+        .post("/vdisks")
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(
+            json!({
+                "path": path.to_str().unwrap(),
+                "format": "vhdx",
+                "disk_type": "dynamic",
+                "virtual_size": 8 * MIB
+            })
+            .to_string(),
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+
+    let response = client
+        .get(disk_uri(&path, "/info"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let info = json_body(response);
+    assert_eq!(info["virtual_size"], 8 * MIB);
+    assert_eq!(info["sector_size"], 512);
+    assert_eq!(info["virtual_storage_type"], 3);
+    assert_eq!(info["provider_sub_type"], 3);
+
+    let child = dir.join("child.vhdx");
+    let response = client
+        .post("/vdisks")
+        .header(auth())
+        .header(ContentType::JSON)
+        .body(
+            json!({
+                "path": child.to_str().unwrap(),
+                "format": "vhdx",
+                "disk_type": "differencing",
+                "parent_path": path.to_str().unwrap()
+            })
+            .to_string(),
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let info = json_body(response);
+    assert_eq!(info["virtual_size"], 8 * MIB);
+    assert_eq!(info["provider_sub_type"], 4);
+    assert_eq!(info["parent_path"], path.to_str().unwrap());
+
+    let response = client
+        .put(disk_uri(&path, "/rct?enabled=true"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client
+        .get(disk_uri(&path, "/rct"))
+        .header(auth())
+        .dispatch();
+    let rct_id = json_body(response)["most_recent_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(!rct_id.is_empty());
+
+    let response = client
+        .get(disk_uri(
+            &path,
+            &format!("/rct/{}/changes", encode(&rct_id)),
+        ))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response), json!([]));
+
+    write_change_list(
+        &path,
+        &json!({
+            "enabled": true,
+            "snapshots": [
+                {"id": rct_id},
+                {"id": "snap-2", "changes": [{"offset": 4194304, "length": 1048576}]}
+            ]
+        }),
+    );
+    let response = client
+        .get(disk_uri(
+            &path,
+            &format!("/rct/{}/changes", encode(&rct_id)),
+        ))
+        .header(auth())
+        .dispatch();
+    assert_eq!(
+        json_body(response),
+        json!([{"offset": 4194304, "length": 1048576}])
+    );
+
+    let response = client
+        .get(disk_uri(
+            &path,
+            &format!("/rct/{}/summary?regions=2", encode(&rct_id)),
+        ))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let summary = json_body(response);
+    assert_eq!(summary["regions"], json!([0, MIB]));
+    assert_eq!(summary["changed_percent"], 12.5);
+}
+
+#[test]
+fn ready_config_check() {
+    let dir = TestDir::new("ready");
+    let check = |client: &Client, name: &str| {
+        let response = client.get("/ready").dispatch();
+        let readiness = json_body(response);
+        readiness["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["name"] == name)
+            .unwrap()
+            .clone()
+    };
+    let client = client(&dir);
+    let response = client.get("/ready").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["ready"], true);
+    // TLS is not enabled in the tests
+    let tls = check(&client, "tls");
+    assert_eq!(tls["ok"], true);
+    assert_eq!(tls["applicable"], false);
+    assert_eq!(check(&client, "disk_directories")["applicable"], true);
+
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("auth_keys", json!({"backup": "secret", "restore": ""})));
+    let client = Client::tracked(service(figment)).unwrap();
+    let config = check(&client, "config");
+    assert_eq!(config["ok"], false);
+    assert_eq!(config["message"], "The auth key restore is empty");
+}
+
+#[test]
+fn manifest_store() {
+    let dir = TestDir::new("manifest");
+    let path = dir.join("disk.raw");
+    fs::write(&path, pattern(7, MIB)).unwrap();
+    write_change_list(
+        &path,
+        &json!({"enabled": true, "snapshots": [{"id": "snap-1"}]}),
+    );
+    // The store can't be created under a file
+    let store = dir.join("store");
+    fs::write(&store, "").unwrap();
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("auth_key", "secret"))
+        .merge(("manifest_store", store.join("manifests").to_str().unwrap()));
+    let client = Client::tracked(service(figment)).unwrap();
+
+    let response = client
+        .post(disk_uri(&path, "/manifest"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+    let message = response.into_string().unwrap();
+    assert!(message.contains(store.to_str().unwrap()));
+}
+
+#[test]
+fn rct_history() {
+    let dir = TestDir::new("history");
+    let path = dir.join("disk.raw");
+    fs::write(&path, vec![0u8; MIB]).unwrap();
+    write_change_list(
+        &path,
+        &json!({"enabled": true, "snapshots": [{"id": "snap-1"}, {"id": "snap-2"}]}),
+    );
+    let history_path = dir.join("history.json");
+    fs::write(&history_path, "{\"corrupt").unwrap();
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("auth_key", "secret"))
+        .merge(("rct_history", history_path.to_str().unwrap()));
+    let client = Client::tracked(service(figment)).unwrap();
+    assert!(dir.join("history.json.corrupt").exists());
+
+    let response = client
+        .get(disk_uri(&path, "/rct"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client
+        .get(disk_uri(&path, "/rct/history?validate=true"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let entries = json_body(response);
+    assert_eq!(entries[0]["rct_id"], "snap-2");
+    assert_eq!(entries[0]["valid"], true);
+
+    // Written with a delay
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let history: Value = serde_json::from_slice(&fs::read(&history_path).unwrap()).unwrap();
+    assert_eq!(history[path.to_str().unwrap()][0]["rct_id"], "snap-2");
+
+    // Errors other than a missing RCT ID fail the validation, here a link
+    // loop in place of the change list
+    let change_list_path = format!("{}.rct.json", path.display());
+    fs::remove_file(&change_list_path).unwrap();
+    std::os::unix::fs::symlink(&change_list_path, &change_list_path).unwrap();
+    let response = client
+        .get(disk_uri(&path, "/rct/history?validate=true"))
+        .header(auth())
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+}
+
+#[test]
+fn audit_log_records() {
+    let dir = TestDir::new("audit");
+    let path = dir.join("disk.raw");
+    fs::write(&path, vec![0u8; MIB]).unwrap();
+    write_change_list(
+        &path,
+        &json!({"enabled": true, "snapshots": [{"id": "snap-1"}]}),
+    );
+    let audit_path = dir.join("audit.log");
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("auth_key", "secret"))
+        .merge(("audit_log", audit_path.to_str().unwrap()));
+    let client = Client::tracked(service(figment)).unwrap();
+
+    for uri in ["/rct?enabled=false", "/rct?enabled=true"].iter() {
+        let response = client.put(disk_uri(&path, uri)).header(auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    client
+        .get(disk_uri(&path, "/rct/history"))
+        .header(auth())
+        .dispatch();
+    client
+        .get(disk_uri(&path, "/rct/snap-1/changes"))
+        .header(auth())
+        .dispatch();
+
+    let records: Vec<Value> = fs::read_to_string(&audit_path)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(records.len(), 4);
+    for record in records.iter() {
+        assert_eq!(record["disk_path"], path.to_str().unwrap());
+    }
+    assert_eq!(records[0]["rct_enabled"], false);
+    assert_eq!(records[1]["rct_enabled"], true);
+    assert_eq!(records[2]["rct_id"], Value::Null);
+    assert_eq!(records[2]["rct_enabled"], Value::Null);
+    assert_eq!(records[3]["rct_id"], "snap-1");
+}